@@ -0,0 +1,105 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+/// Writes `contents` to `path` without ever leaving a half written file there. The data goes to
+/// a temporary file in `temp_dir`, or next to `path` by default, and is then renamed over
+/// `path`. The result gets the permissions of `like`, the file it was made from.
+///
+/// A rename can't cross filesystems. If `temp_dir` is on a different one the temporary file is
+/// copied into place instead, which is not atomic.
+///
+/// Fails up front when the filesystem doesn't have room for `contents`.
+///
+/// When `path` is a FIFO or a device, e.g. a named pipe a media player reads from, `contents`
+/// are written to it directly, since renaming over it would replace it with a regular file.
+pub fn write(path: &Path, contents: &[u8], like: &Path, temp_dir: Option<&Path>) -> io::Result<()> {
+    let special =
+        std::fs::metadata(path).is_ok_and(|metadata| !metadata.is_file() && !metadata.is_dir());
+    if special {
+        return OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .write_all(contents);
+    }
+
+    let dir = match temp_dir {
+        Some(dir) => dir,
+        None => match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        },
+    };
+
+    // Refuse before writing anything rather than after filling the disk
+    let needed = contents.len() as u64;
+    crate::space::ensure(dir, needed)?;
+    let mut crosses_devices = false;
+    if temp_dir.is_some() {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            crate::space::ensure(parent, needed)?;
+            crosses_devices = !same_filesystem(dir, parent);
+        }
+    }
+    if crosses_devices {
+        warn_not_atomic(dir, path);
+    }
+
+    let mut temp = NamedTempFile::new_in(dir)?;
+    temp.write_all(contents)?;
+    temp.as_file().sync_all()?;
+    if let Ok(metadata) = std::fs::metadata(like) {
+        temp.as_file().set_permissions(metadata.permissions())?;
+    }
+
+    // Dropping the temporary file removes it, so nothing is left behind
+    if crate::interrupt::requested() {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "interrupted by Ctrl-C",
+        ));
+    }
+
+    match temp.persist(path) {
+        Ok(_) => Ok(()),
+        Err(err) if err.error.kind() == io::ErrorKind::CrossesDevices => {
+            if !crosses_devices {
+                warn_not_atomic(dir, path);
+            }
+            std::fs::copy(err.file.path(), path)?;
+            Ok(())
+        }
+        Err(err) => Err(err.error),
+    }
+}
+
+fn warn_not_atomic(dir: &Path, path: &Path) {
+    log::warn!(
+        "\'{}\' is on a different filesystem than \'{}\', copying instead of renaming, so \
+         the write is not atomic",
+        dir.display(),
+        path.display()
+    );
+}
+
+/// Whether `a` and `b` are on the same filesystem. Only known on Unix, elsewhere it shows when
+/// the rename fails.
+#[cfg(unix)]
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => true,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(_a: &Path, _b: &Path) -> bool {
+    true
+}
@@ -0,0 +1,33 @@
+use std::io;
+use std::path::Path;
+
+/// Carries the readonly and hidden flags of `from` over to `to`. Encrypting and decrypting write
+/// a new file next to the old one, which otherwise starts out with default attributes. A no-op
+/// outside of Windows.
+#[cfg(windows)]
+pub fn copy(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::SetFileAttributesW;
+    use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_HIDDEN;
+    use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_READONLY;
+
+    let preserved = std::fs::metadata(from)?.file_attributes()
+        & (FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_READONLY);
+    if preserved == 0 {
+        return Ok(());
+    }
+
+    let current = std::fs::metadata(to)?.file_attributes();
+    let wide: Vec<u16> = to.as_os_str().encode_wide().chain(Some(0)).collect();
+    // SAFETY: `wide` is a NUL terminated UTF-16 path that outlives the call
+    if unsafe { SetFileAttributesW(wide.as_ptr(), current | preserved) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub fn copy(_from: &Path, _to: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use crate::error::AlpacaError;
+use sha2::Digest;
+use sha2::Sha256;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Append-only log of every file operation for `--log-file`, one JSON object per line.
+/// Shared between the threads of a schematic run.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends one line. `key` is only recorded as a short fingerprint, which tells entries
+    /// that used the same key apart without revealing it.
+    pub fn record(
+        &self,
+        action: &str,
+        filepath: &Path,
+        result: Result<(), &AlpacaError>,
+        key: Option<&[u8]>,
+        elapsed: Duration,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let (status, error) = match result {
+            Ok(()) => ("ok", None),
+            Err(err) => ("failed", Some(err.kind())),
+        };
+        let line = serde_json::json!({
+            "timestamp": timestamp,
+            "action": action,
+            "file": filepath.display().to_string(),
+            "status": status,
+            "error": error,
+            "key_id": key.map(|key| hex::encode(&Sha256::digest(key)[..4])),
+            "duration_ms": elapsed.as_millis() as u64,
+        });
+
+        // A log that can't be written to shouldn't stop the files from being processed
+        let mut file = self.file.lock().unwrap_or_else(|err| err.into_inner());
+        if let Err(err) = writeln!(file, "{}", line) {
+            log::warn!("Failed to write to the log file: {}", err);
+        }
+    }
+}
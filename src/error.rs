@@ -0,0 +1,180 @@
+use std::fmt;
+use std::io;
+use std::process::ExitStatus;
+
+#[derive(Debug)]
+pub enum AlpacaError {
+    /// An I/O operation failed, with a short description of what was being done
+    Io(&'static str, io::Error),
+    /// The key string could not be parsed into a key and nonce
+    MalformedKey(&'static str),
+    /// AES-GCM refused to encrypt the input
+    EncryptionFailed,
+    /// The input is too short to hold even an empty encrypted file
+    Truncated,
+    /// The file starts with the header magic but the header can't be read
+    InvalidHeader(&'static str),
+    /// The file was written by a newer Alpaca with a format version this build can't read
+    UnsupportedVersion(u8),
+    /// The input is not a valid gzip stream
+    Corrupt(io::Error),
+    /// The ciphertext did not authenticate, either the key is wrong or the file was modified
+    AuthenticationFailed,
+    /// A schematic file is not valid YAML or doesn't match the schematic layout
+    InvalidSchematic(serde_yaml::Error),
+    /// A schematic entry has an action other than ENCRYPT or DECRYPT
+    UnknownAction(String),
+    /// A schematic includes itself, directly or through other schematics
+    IncludeCycle,
+    /// The command given with `--key-exec` exited unsuccessfully
+    KeyExecFailed(ExitStatus),
+    /// A path can't be used the way it was asked for
+    InvalidPath(&'static str),
+    /// A path refers to an environment variable that isn't set
+    UnsetVariable(String),
+    /// The random number generator failed its startup sanity check
+    BrokenRng(&'static str),
+    /// An algorithm or codec given on the command line disagrees with the file's header
+    HeaderMismatch {
+        field: &'static str,
+        given: String,
+        found: String,
+    },
+    /// The file decrypted, but the plaintext doesn't match the SHA-256 in its header
+    ChecksumMismatch,
+    /// The encrypted file didn't decrypt back to the original, so it was removed
+    VerificationFailed,
+    /// The text given to `decrypt-text` isn't what `encrypt-text` prints
+    MalformedText(&'static str),
+    /// Some schematic entries can't run, so none of them were
+    InvalidEntries {
+        invalid: usize,
+        total: usize,
+        reason: &'static str,
+    },
+    /// `--in-place` would replace files without a terminal to ask on and without `--yes`
+    NeedsConfirmation(String),
+}
+
+impl AlpacaError {
+    /// Stable name of the error, used by `--error-format json`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AlpacaError::Io(..) => "Io",
+            AlpacaError::MalformedKey(_) => "MalformedKey",
+            AlpacaError::EncryptionFailed => "EncryptionFailed",
+            AlpacaError::Truncated => "Truncated",
+            AlpacaError::InvalidHeader(_) => "InvalidHeader",
+            AlpacaError::UnsupportedVersion(_) => "UnsupportedVersion",
+            AlpacaError::Corrupt(_) => "Corrupt",
+            AlpacaError::AuthenticationFailed => "AuthenticationFailed",
+            AlpacaError::InvalidSchematic(_) => "InvalidSchematic",
+            AlpacaError::UnknownAction(_) => "UnknownAction",
+            AlpacaError::IncludeCycle => "IncludeCycle",
+            AlpacaError::KeyExecFailed(_) => "KeyExecFailed",
+            AlpacaError::InvalidPath(_) => "InvalidPath",
+            AlpacaError::UnsetVariable(_) => "UnsetVariable",
+            AlpacaError::BrokenRng(_) => "BrokenRng",
+            AlpacaError::HeaderMismatch { .. } => "HeaderMismatch",
+            AlpacaError::ChecksumMismatch => "ChecksumMismatch",
+            AlpacaError::VerificationFailed => "VerificationFailed",
+            AlpacaError::MalformedText(_) => "MalformedText",
+            AlpacaError::InvalidEntries { .. } => "InvalidEntries",
+            AlpacaError::NeedsConfirmation(_) => "NeedsConfirmation",
+        }
+    }
+}
+
+impl fmt::Display for AlpacaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlpacaError::Io(context, err) => write!(f, "{}: {}", context, err),
+            AlpacaError::MalformedKey(detail) => write!(f, "{}", detail),
+            AlpacaError::EncryptionFailed => write!(f, "Failed to encrypt"),
+            AlpacaError::Truncated => write!(
+                f,
+                "File is too short to be encrypted data, it may be truncated"
+            ),
+            AlpacaError::InvalidHeader(detail) => write!(f, "Invalid header: {}", detail),
+            AlpacaError::UnsupportedVersion(version) => write!(
+                f,
+                "File uses format version {}, this build only reads up to version {}. \
+                 Upgrade Alpaca to decrypt it",
+                version,
+                crate::header::VERSION
+            ),
+            AlpacaError::Corrupt(err) => write!(
+                f,
+                "Input is not valid gzip data, the file may be corrupt: {}",
+                err
+            ),
+            AlpacaError::AuthenticationFailed => write!(
+                f,
+                "Failed to decrypt, the key is wrong or the file has been tampered with"
+            ),
+            AlpacaError::InvalidSchematic(err) => write!(f, "Failed to parse YAML: {}", err),
+            AlpacaError::UnknownAction(action) => write!(
+                f,
+                "Unknown action \'{}\', expected ENCRYPT or DECRYPT",
+                action
+            ),
+            AlpacaError::IncludeCycle => write!(f, "Schematic includes itself"),
+            AlpacaError::KeyExecFailed(status) => write!(
+                f,
+                "Key command failed ({}), the file was not encrypted",
+                status
+            ),
+            AlpacaError::InvalidPath(detail) => write!(f, "{}", detail),
+            AlpacaError::UnsetVariable(name) => write!(
+                f,
+                "Environment variable \'{}\' is not set or not valid Unicode",
+                name
+            ),
+            AlpacaError::BrokenRng(detail) => {
+                write!(f, "{}, refusing to generate keys with it", detail)
+            }
+            AlpacaError::HeaderMismatch {
+                field,
+                given,
+                found,
+            } => write!(
+                f,
+                "{} given on the command line is {}, but the header says {}",
+                field, given, found
+            ),
+            AlpacaError::ChecksumMismatch => write!(
+                f,
+                "Decrypted data doesn't match the checksum stored when it was encrypted"
+            ),
+            AlpacaError::VerificationFailed => write!(
+                f,
+                "Encrypted file doesn't decrypt back to the original. It was removed, the source was kept"
+            ),
+            AlpacaError::MalformedText(detail) => write!(f, "{}", detail),
+            AlpacaError::InvalidEntries {
+                invalid,
+                total,
+                reason,
+            } => write!(
+                f,
+                "{} of {} entries {}, nothing was run",
+                invalid, total, reason
+            ),
+            AlpacaError::NeedsConfirmation(what) => write!(
+                f,
+                "--in-place would replace {} without a terminal to confirm on, pass --yes to allow it",
+                what
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AlpacaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AlpacaError::Io(_, err) | AlpacaError::Corrupt(err) => Some(err),
+            AlpacaError::InvalidSchematic(err) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use crate::error::AlpacaError;
+use std::fmt;
+
+/// Every file written with a header starts with these bytes. Files from before the header
+/// existed are bare gzip streams and start with `1f 8b` instead.
+pub const MAGIC: &[u8; 3] = b"ALP";
+/// Format version written by this build
+pub const VERSION: u8 = 1;
+/// Longest hint that fits in the header, in bytes
+pub const MAX_HINT_LEN: usize = 255;
+
+/// Metadata tag of the user supplied, unencrypted hint
+const TAG_HINT: u8 = 1;
+/// Metadata tag of the SHA-256 of the plaintext
+const TAG_SHA256: u8 = 2;
+/// Metadata tag of the salt the key was derived from a secret file with
+const TAG_SALT: u8 = 3;
+/// Metadata tag marking a file whose plaintext is stored in the clear, only authenticated
+const TAG_AUTH_ONLY: u8 = 4;
+/// Metadata tag of the original file name, encrypted
+const TAG_NAME: u8 = 5;
+/// Metadata tag of the file key wrapped for one recipient, repeated per recipient
+const TAG_WRAPPED_KEY: u8 = 6;
+/// A wrapped 16 byte key plus its GCM tag
+pub const WRAPPED_KEY_LEN: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Debug, clap::ValueEnum)]
+pub enum Algorithm {
+    Aes128Gcm,
+}
+
+impl Algorithm {
+    fn id(self) -> u8 {
+        match self {
+            Algorithm::Aes128Gcm => 0,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Algorithm> {
+        match id {
+            0 => Some(Algorithm::Aes128Gcm),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Algorithm::Aes128Gcm => write!(f, "AES-128-GCM"),
+        }
+    }
+}
+
+/// How the plaintext was compressed before encryption
+#[derive(Clone, Copy, PartialEq, Debug, clap::ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Stored as is, used when compressing wouldn't pay off
+    None,
+    Gzip,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Gzip => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Gzip),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::None => write!(f, "none"),
+            Codec::Gzip => write!(f, "gzip"),
+        }
+    }
+}
+
+/// Header in front of the ciphertext. Version 1 layout, integers are little endian:
+///
+/// | size | field                                                  |
+/// |------|--------------------------------------------------------|
+/// | 3    | magic `ALP`                                            |
+/// | 1    | format version                                         |
+/// | 1    | algorithm id                                           |
+/// | 1    | codec id                                               |
+/// | 1    | nonce length `n`                                       |
+/// | n    | nonce                                                  |
+/// | 2    | metadata length `m`                                    |
+/// | m    | metadata entries, each `tag: u8, len: u16, len bytes`  |
+///
+/// Readers skip metadata tags they don't know, so optional fields can be added without a new
+/// version. The encoded header is passed to AES-GCM as associated data, so changing any of it
+/// makes decryption fail.
+///
+/// Known metadata tags:
+///
+/// | tag | field                 |
+/// |-----|-----------------------|
+/// | 1   | hint, UTF-8           |
+/// | 2   | SHA-256 of plaintext  |
+/// | 3   | HKDF salt             |
+/// | 4   | auth-only, empty      |
+/// | 5   | encrypted file name   |
+/// | 6   | wrapped key, repeats  |
+///
+/// Auth-only files are the header, the plaintext and a GCM tag over both. Older readers
+/// don't know the tag and take the plaintext for ciphertext, which fails authentication.
+#[derive(Debug, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    pub algorithm: Algorithm,
+    pub codec: Codec,
+    pub nonce: Vec<u8>,
+    /// Free-form label to tell which key opens the file. Stored in the clear, never secret.
+    pub hint: Option<String>,
+    /// Checked against the decrypted plaintext. Stored in the clear like the hint, so anyone
+    /// can test a guess of the contents against it.
+    pub sha256: Option<[u8; 32]>,
+    /// Present when the key was derived from a secret file instead of given directly
+    pub salt: Option<Vec<u8>>,
+    /// The plaintext follows the header unencrypted, only tampering with it is detected
+    pub auth_only: bool,
+    /// Name the file had before `--hash-name` replaced it, encrypted with the file's key
+    pub name: Option<Vec<u8>>,
+    /// The file's key encrypted once per recipient, with each recipient's key and the nonce
+    /// above. Empty unless the file was encrypted for recipients.
+    pub wrapped_keys: Vec<Vec<u8>>,
+}
+
+impl Header {
+    /// Encodes the header. Fails when the metadata, e.g. with enough recipients, outgrows its
+    /// 16 bit length.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AlpacaError> {
+        let mut metadata: Vec<u8> = Vec::new();
+        if let Some(hint) = &self.hint {
+            push_entry(&mut metadata, TAG_HINT, hint.as_bytes());
+        }
+        if let Some(sha256) = &self.sha256 {
+            push_entry(&mut metadata, TAG_SHA256, sha256);
+        }
+        if let Some(salt) = &self.salt {
+            push_entry(&mut metadata, TAG_SALT, salt);
+        }
+        if self.auth_only {
+            push_entry(&mut metadata, TAG_AUTH_ONLY, &[]);
+        }
+        if let Some(name) = &self.name {
+            push_entry(&mut metadata, TAG_NAME, name);
+        }
+        for wrapped_key in &self.wrapped_keys {
+            push_entry(&mut metadata, TAG_WRAPPED_KEY, wrapped_key);
+        }
+
+        // An entry too long for its own length also makes the metadata too long, so checking the
+        // total covers the entries
+        let metadata_len = u16::try_from(metadata.len())
+            .map_err(|_| AlpacaError::InvalidHeader("Metadata is larger than 64 KiB"))?;
+        let nonce_len = u8::try_from(self.nonce.len())
+            .map_err(|_| AlpacaError::InvalidHeader("Nonce is longer than 255 bytes"))?;
+
+        let mut bytes = Vec::with_capacity(9 + self.nonce.len() + metadata.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(self.version);
+        bytes.push(self.algorithm.id());
+        bytes.push(self.codec.id());
+        bytes.push(nonce_len);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&metadata_len.to_le_bytes());
+        bytes.extend_from_slice(&metadata);
+        Ok(bytes)
+    }
+
+    /// Splits `input` into its header and the rest of the file. Returns `None` when the input
+    /// doesn't start with the magic bytes, i.e. it's a headerless legacy file.
+    ///
+    /// The length of the encoded header is returned alongside it, `&input[..len]` is the
+    /// associated data for decryption.
+    pub fn parse(input: &[u8]) -> Result<Option<(Header, usize)>, AlpacaError> {
+        if !input.starts_with(MAGIC) {
+            return Ok(None);
+        }
+
+        let mut reader = Reader {
+            input,
+            position: MAGIC.len(),
+        };
+
+        let header = match reader.byte()? {
+            1 => Header::parse_v1(&mut reader)?,
+            0 => return Err(AlpacaError::InvalidHeader("Format version 0 doesn't exist")),
+            version => return Err(AlpacaError::UnsupportedVersion(version)),
+        };
+        Ok(Some((header, reader.position)))
+    }
+
+    fn parse_v1(reader: &mut Reader) -> Result<Header, AlpacaError> {
+        let algorithm = Algorithm::from_id(reader.byte()?)
+            .ok_or(AlpacaError::InvalidHeader("Unknown algorithm"))?;
+        let codec =
+            Codec::from_id(reader.byte()?).ok_or(AlpacaError::InvalidHeader("Unknown codec"))?;
+        let nonce_len = reader.byte()? as usize;
+        let nonce = reader.take(nonce_len)?.to_vec();
+
+        let metadata_len = reader.u16()? as usize;
+        let mut metadata = Reader {
+            input: reader.take(metadata_len)?,
+            position: 0,
+        };
+        let mut hint = None;
+        let mut sha256 = None;
+        let mut salt = None;
+        let mut auth_only = false;
+        let mut name = None;
+        let mut wrapped_keys = Vec::new();
+        while !metadata.is_empty() {
+            let tag = metadata.byte()?;
+            let len = metadata.u16()? as usize;
+            let value = metadata.take(len)?;
+
+            match tag {
+                TAG_HINT => {
+                    let value = String::from_utf8(value.to_vec())
+                        .map_err(|_| AlpacaError::InvalidHeader("Hint is not valid UTF-8"))?;
+                    hint = Some(value);
+                }
+                TAG_SHA256 => {
+                    let value = value
+                        .try_into()
+                        .map_err(|_| AlpacaError::InvalidHeader("SHA-256 must be 32 bytes long"))?;
+                    sha256 = Some(value);
+                }
+                TAG_SALT => salt = Some(value.to_vec()),
+                TAG_AUTH_ONLY => auth_only = true,
+                TAG_NAME => name = Some(value.to_vec()),
+                TAG_WRAPPED_KEY => {
+                    if value.len() != WRAPPED_KEY_LEN {
+                        return Err(AlpacaError::InvalidHeader(
+                            "Wrapped key must be 32 bytes long",
+                        ));
+                    }
+                    wrapped_keys.push(value.to_vec());
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Header {
+            version: 1,
+            algorithm,
+            codec,
+            nonce,
+            hint,
+            sha256,
+            salt,
+            auth_only,
+            name,
+            wrapped_keys,
+        })
+    }
+}
+
+fn push_entry(metadata: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    metadata.push(tag);
+    metadata.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    metadata.extend_from_slice(value);
+}
+
+struct Reader<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], AlpacaError> {
+        let bytes = self
+            .input
+            .get(self.position..self.position + len)
+            .ok_or(AlpacaError::Truncated)?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, AlpacaError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, AlpacaError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.input.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        Header {
+            version: VERSION,
+            algorithm: Algorithm::Aes128Gcm,
+            codec: Codec::Gzip,
+            nonce: vec![7; 12],
+            hint: Some("laptop backup".to_owned()),
+            sha256: Some([9; 32]),
+            salt: Some(vec![3; 16]),
+            auth_only: true,
+            name: Some(vec![5; 24]),
+            wrapped_keys: vec![vec![1; WRAPPED_KEY_LEN], vec![2; WRAPPED_KEY_LEN]],
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut bytes = header().to_bytes().unwrap();
+        let header_len = bytes.len();
+        bytes.extend_from_slice(b"ciphertext");
+
+        let (parsed, len) = Header::parse(&bytes).unwrap().unwrap();
+        assert_eq!(parsed, header());
+        assert_eq!(len, header_len);
+    }
+
+    #[test]
+    fn skips_unknown_tags() {
+        let mut metadata = Vec::new();
+        push_entry(&mut metadata, 200, b"from the future");
+        push_entry(&mut metadata, TAG_HINT, b"hint");
+
+        let mut bytes = Header {
+            hint: None,
+            sha256: None,
+            salt: None,
+            auth_only: false,
+            name: None,
+            wrapped_keys: Vec::new(),
+            ..header()
+        }
+        .to_bytes()
+        .unwrap();
+        let metadata_at = bytes.len() - 2;
+        bytes.truncate(metadata_at);
+        bytes.extend_from_slice(&(metadata.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&metadata);
+
+        let (parsed, _) = Header::parse(&bytes).unwrap().unwrap();
+        assert_eq!(parsed.hint.as_deref(), Some("hint"));
+    }
+
+    #[test]
+    fn rejects_oversized_metadata() {
+        let header = Header {
+            wrapped_keys: vec![vec![1; WRAPPED_KEY_LEN]; u16::MAX as usize / WRAPPED_KEY_LEN],
+            ..header()
+        };
+        assert!(matches!(
+            header.to_bytes(),
+            Err(AlpacaError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn headerless_input_is_legacy() {
+        assert!(Header::parse(&[0x1f, 0x8b, 8, 0]).unwrap().is_none());
+        assert!(Header::parse(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let bytes = header().to_bytes().unwrap();
+        assert!(matches!(
+            Header::parse(&bytes[..bytes.len() - 1]),
+            Err(AlpacaError::Truncated)
+        ));
+        assert!(matches!(
+            Header::parse(b"ALP\x00"),
+            Err(AlpacaError::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            Header::parse(b"ALP\x09"),
+            Err(AlpacaError::UnsupportedVersion(9))
+        ));
+        assert!(matches!(
+            Header::parse(b"ALP\x01\x07"),
+            Err(AlpacaError::InvalidHeader(_))
+        ));
+    }
+}
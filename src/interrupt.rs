@@ -0,0 +1,28 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+/// Exit status of a run stopped by Ctrl-C, 128 + SIGINT like a shell reports it
+pub const EXIT_STATUS: i32 = 130;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Makes Ctrl-C stop the run between files instead of killing it mid-write, which would leave
+/// temporary files behind. The file being written when it arrives is abandoned and its
+/// temporary file removed; sources are never touched. A second Ctrl-C exits immediately.
+pub fn install() {
+    let result = ctrlc::set_handler(|| {
+        if REQUESTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_STATUS);
+        }
+        log::warn!("Interrupted, stopping after cleaning up. Press Ctrl-C again to force");
+    });
+    if let Err(err) = result {
+        log::warn!("Failed to install the Ctrl-C handler: {}", err);
+    }
+}
+
+/// Whether Ctrl-C was pressed, checked before each file and before anything is renamed into
+/// place
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
@@ -1,59 +1,684 @@
+mod atomic;
+mod attributes;
+mod audit;
+mod error;
+mod header;
+mod interrupt;
+mod manifest;
+mod space;
+
+use aes_gcm::aead::rand_core::CryptoRngCore;
 use aes_gcm::aead::Aead;
+use aes_gcm::aead::Payload;
 use aes_gcm::AeadCore;
 use aes_gcm::Aes128Gcm;
 use aes_gcm::KeyInit;
+use audit::AuditLog;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use clap::CommandFactory;
 use clap::Parser;
+use error::AlpacaError;
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
+use header::Algorithm;
+use header::Codec;
+use header::Header;
+use hkdf::Hkdf;
+use manifest::Manifest;
 use rand::rngs::OsRng;
+use rand::rngs::StdRng;
+use rand::RngCore;
+use rand::SeedableRng;
 use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
 use serde::Deserialize;
 use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::ffi::OsString;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::BufReader;
+use std::io::IsTerminal;
 use std::io::Read;
 use std::io::Write;
+use std::path::Component;
+use std::path::Path;
 use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
-enum Args {
+struct Args {
+    /// How errors are reported on stderr
+    #[clap(long, global = true, value_enum, default_value_t = ErrorFormat::Human)]
+    error_format: ErrorFormat,
+    /// Don't ask before replacing source files with --in-place. Required for --in-place
+    /// when there's no terminal to ask on
+    #[clap(short = 'y', long, global = true)]
+    yes: bool,
+    /// Print how long each operation took and its throughput
+    #[clap(short = 'v', long, global = true)]
+    verbose: bool,
+    /// Print how long reading, compressing, encrypting and writing took for each file,
+    /// and the totals at the end
+    #[clap(long, global = true)]
+    timings: bool,
+    /// Append a JSON line per file to this log: time, action, file, status, a fingerprint
+    /// of the key and how long it took. Keys themselves are never written to it
+    #[clap(long, global = true)]
+    log_file: Option<PathBuf>,
+    /// How printed keys are written. Keys given to Alpaca are accepted in either encoding
+    #[clap(long, global = true, value_enum, default_value_t = KeyEncoding::Hex)]
+    key_encoding: KeyEncoding,
+    /// Where output is written before being renamed into place. Defaults to the output's
+    /// own directory; elsewhere on a different filesystem the rename becomes a plain copy
+    #[clap(long, global = true, visible_alias = "tmp-dir")]
+    temp_dir: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
     Encrypt {
-        #[clap(index = 1)]
-        filepath: PathBuf,
+        /// Encrypt every file with this key instead of generating one per file.
+        /// Each file still gets its own random nonce, stored in its header
+        #[clap(long, conflicts_with = "key_file", value_parser = parse_gcm_key)]
+        key: Option<GcmKey>,
+        /// Like --key, but read the key from a file
+        #[clap(long)]
+        key_file: Option<PathBuf>,
+        /// Derive each file's key from the raw bytes of this file, e.g. a secret kept on a
+        /// USB stick, with a random salt stored in the header. Decrypting then needs the
+        /// same file instead of a key
+        #[clap(
+            long,
+            conflicts_with_all = ["key", "key_file", "key_exec", "print_key_only", "qr", "no_header"]
+        )]
+        secret_file: Option<PathBuf>,
+        /// Instead of printing each generated key, run this command and write the key
+        /// to its stdin. `{file}` is replaced by the path of the file being encrypted,
+        /// e.g. "pass insert -e backup/{file}"
+        #[clap(long, conflicts_with_all = ["key", "key_file"])]
+        key_exec: Option<String>,
+        /// Print nothing but the generated keys on stdout, one line per encrypted file in the
+        /// order given, e.g. KEY=$(alpaca encrypt --print-key-only file). Status goes to stderr
+        #[clap(long, conflicts_with_all = ["key", "key_file", "key_exec"])]
+        print_key_only: bool,
+        /// Also show each generated key as a QR code, for scanning into a phone.
+        /// Skipped when stdout isn't a terminal wide enough to show it
+        #[clap(long, conflicts_with_all = ["key", "key_file", "key_exec", "print_key_only"])]
+        qr: bool,
+        /// Encrypt for the holder of this key. Each file gets a random key, stored in the
+        /// header once per recipient wrapped with their key, so any one of them can
+        /// decrypt it with their own. Repeat for more recipients
+        #[clap(
+            long = "recipient",
+            value_name = "KEY",
+            value_parser = parse_gcm_key,
+            conflicts_with_all = [
+                "key", "key_file", "secret_file", "key_exec", "print_key_only", "qr", "no_header"
+            ]
+        )]
+        recipients: Vec<GcmKey>,
+        /// Encrypt with this nonce instead of a random one. Reusing a nonce
+        /// with the same key breaks AES-GCM completely, only use this for test vectors
+        #[clap(
+            long,
+            hide = true,
+            requires = "i_know_what_im_doing",
+            value_parser = parse_gcm_nonce
+        )]
+        nonce: Option<GcmNonce>,
+        /// Generate keys, nonces and salts from an RNG seeded with this number, so the same
+        /// inputs always encrypt to the same bytes. Anyone who knows the seed knows every
+        /// key, only use this for golden-file tests. Not allowed with a key that outlives the
+        /// run, since two runs with the same seed would reuse nonces under it
+        #[clap(
+            long,
+            hide = true,
+            requires = "i_know_what_im_doing",
+            conflicts_with_all = ["key", "key_file", "recipients", "secret_file"]
+        )]
+        seed: Option<u64>,
+        #[clap(long, hide = true)]
+        i_know_what_im_doing: bool,
+        /// Replace the source file instead of writing the result next to it
+        #[clap(long, visible_alias = "delete-source")]
+        in_place: bool,
+        /// Read each encrypted file back and check that it decrypts to the original.
+        /// If it doesn't, the encrypted file is removed and the source always kept
+        #[clap(long, visible_alias = "verify-before-delete")]
+        verify: bool,
+        /// Write the encrypted files under this directory instead of next to the sources,
+        /// mirroring their paths. Missing directories are created
+        #[clap(long, conflicts_with = "in_place")]
+        output_dir: Option<PathBuf>,
+        /// Skip files larger than this many bytes
+        #[clap(long)]
+        max_file_size: Option<u64>,
+        /// Only encrypt files modified after this UTC date or time, e.g. 2024-05-01 or
+        /// 2024-05-01T18:30:00, for incremental backups
+        #[clap(long, value_parser = parse_since)]
+        since: Option<SystemTime>,
+        /// Label stored in the header to help tell which key opens the file.
+        /// It is NOT encrypted, anyone can read it with `alpaca info`
+        #[clap(long, value_parser = parse_hint)]
+        hint: Option<String>,
+        /// Store a SHA-256 of the plaintext in the header, checked after decryption to
+        /// catch bugs in Alpaca itself. Like the hint it is NOT encrypted, so don't use
+        /// it on files whose contents could be guessed
+        #[clap(long)]
+        checksum: bool,
+        /// Skip files whose contents still match their entry in the --manifest of an earlier
+        /// run, as long as the encrypted copy is still there. Their entries are kept
+        #[clap(long, requires = "manifest", conflicts_with = "in_place")]
+        dedup: bool,
+        /// Files smaller than this many bytes are stored uncompressed, since gzip
+        /// would only make them bigger
+        #[clap(long, default_value_t = DEFAULT_COMPRESS_MIN_SIZE)]
+        compress_min_size: usize,
+        /// Store a file uncompressed when gzip doesn't get it down to at most this
+        /// fraction of its size, e.g. media or archives. 1 keeps anything that shrinks
+        #[clap(long, default_value_t = DEFAULT_MIN_COMPRESSION_RATIO)]
+        min_compression_ratio: f64,
+        /// Name encrypted files after this template instead of appending .alp, e.g.
+        /// "{stem}_{date}.{ext}.alp". Tokens are {stem}, {ext}, {date} (UTC) and {hash}
+        /// (of the contents). The original name is kept encrypted in the header and restored
+        /// by decrypt. Files without a header and a name not ending in .alp need decrypt -o
+        #[clap(long, value_parser = parse_name_template, conflicts_with = "in_place")]
+        name_template: Option<String>,
+        /// Name encrypted files after a hash instead of the original name, which is kept
+        /// encrypted in the header and restored by decrypt
+        #[clap(long, conflicts_with = "name_template")]
+        hash_name: bool,
+        /// Write only the nonce and ciphertext, without the `ALP` header. The file no
+        /// longer describes itself and is always gzip compressed, decrypt it with
+        /// `--raw --algorithm aes128-gcm --compression gzip`
+        #[clap(long, conflicts_with_all = ["hint", "checksum", "hash_name"])]
+        no_header: bool,
+        /// Don't encrypt, leave the contents readable after the header and only store a
+        /// tag that `alpaca verify` and decrypt check for tampering
+        #[clap(long, conflicts_with_all = ["no_header", "in_place"])]
+        auth_only: bool,
+        /// Keep going when a file fails to encrypt, report every failure and print a
+        /// summary at the end. By default the first failure stops the run
+        #[clap(long)]
+        continue_on_error: bool,
+        /// Check the inputs and show where each file would go and how it would be
+        /// compressed, without generating keys or writing anything
+        #[clap(long)]
+        dry_run: bool,
+        /// Write a JSON inventory of the encrypted files to this path: original and
+        /// output path, algorithm, compression, SHA-256 and size of the contents, and
+        /// time. Keys are left out unless --manifest-include-keys is given
+        #[clap(long, conflicts_with = "dry_run")]
+        manifest: Option<PathBuf>,
+        /// Also store each file's key in the manifest, which then unlocks every file in it
+        #[clap(long, requires = "manifest")]
+        manifest_include_keys: bool,
+        #[clap(index = 1, required = true)]
+        filepaths: Vec<PathBuf>,
     },
     Decrypt {
-        #[clap(short = 'k', long, required = true)]
-        key: String,
+        #[clap(
+            short = 'k',
+            long,
+            required_unless_present_any = ["ask_each", "secret_file"]
+        )]
+        key: Option<String>,
+        /// The file the key was derived from, for files encrypted with --secret-file
+        #[clap(long, conflicts_with_all = ["key", "ask_each"])]
+        secret_file: Option<PathBuf>,
+        /// Decrypt every .alp file in a directory, asking for each file's key in turn
+        /// and showing its hint. Files whose key is left blank are skipped
+        #[clap(long, conflicts_with = "key")]
+        ask_each: bool,
+        /// Write the plaintext to stdout instead of a file, leaving the encrypted file as is
+        #[clap(long, conflicts_with_all = ["ask_each", "in_place"])]
+        stdout: bool,
+        /// Replace the source file instead of writing the result next to it
+        #[clap(long, visible_alias = "delete-source")]
+        in_place: bool,
+        /// The file was written with --no-header, so --algorithm and --compression
+        /// have to be given since they can't be read from it
+        #[clap(long, requires_all = ["algorithm", "compression"])]
+        raw: bool,
+        /// Treat the file as one made by 0.1, gzip over AES-128-GCM without a header, and
+        /// skip looking for one. Such files are recognised anyway, this makes it explicit
+        #[clap(long, conflicts_with_all = ["raw", "secret_file", "algorithm", "compression"])]
+        legacy: bool,
+        /// Not needed for files with a header, if given anyway it must match the header
+        #[clap(long, value_enum)]
+        algorithm: Option<Algorithm>,
+        /// Not needed for files with a header, if given anyway it must match the header
+        #[clap(long, value_enum)]
+        compression: Option<Codec>,
+        /// Name the output like the default, without .alp, plus this suffix, e.g. ".dec"
+        /// to keep it from replacing a file of the same name
+        #[clap(long, conflicts_with_all = ["stdout", "output"])]
+        suffix: Option<String>,
+        /// Write the plaintext to this path instead of one named after the encrypted file.
+        /// A named pipe is written to directly, e.g. to feed a player without a copy on disk
+        #[clap(short = 'o', long, conflicts_with_all = ["stdout", "ask_each"])]
+        output: Option<PathBuf>,
+        /// Check the key and header and show where the plaintext would go, without
+        /// decrypting or writing anything
+        #[clap(long, conflicts_with = "ask_each")]
+        dry_run: bool,
         #[clap(index = 1)]
         filepath: PathBuf,
     },
     LoadSchematic {
+        /// Replace the source files instead of writing the results next to them
+        #[clap(long, visible_alias = "delete-source")]
+        in_place: bool,
+        /// Run entries one at a time in file order, for stable output and predictable memory
+        /// use on small devices. Each file is still held in memory whole while it's processed
+        #[clap(long, visible_alias = "lowmem")]
+        sequential: bool,
+        /// How many entries run at once, defaults to the number of CPUs
+        #[clap(
+            long,
+            value_parser = clap::value_parser!(u16).range(1..),
+            conflicts_with = "sequential"
+        )]
+        parallel_files: Option<u16>,
+        /// Skip entries whose file is larger than this many bytes
+        #[clap(long)]
+        max_file_size: Option<u64>,
+        /// Only run entries whose file was modified after this UTC date or time, e.g.
+        /// 2024-05-01 or 2024-05-01T18:30:00
+        #[clap(long, value_parser = parse_since)]
+        since: Option<SystemTime>,
+        /// Don't read encrypted files back to check they decrypt, which is done by default
+        #[clap(long)]
+        no_verify: bool,
+        /// Only print a count of what happened at the end, plus errors and the keys of
+        /// encrypted entries unless --keys-out or --manifest-include-keys saves them
+        #[clap(long)]
+        summary_only: bool,
+        /// Also write the keys of encrypted entries to this file, as a schematic of
+        /// DECRYPT entries sorted by path that load-schematic can run to undo the batch
+        #[clap(long)]
+        keys_out: Option<PathBuf>,
+        /// Write a JSON inventory of the entries that were encrypted to this path: original and
+        /// output path, algorithm, compression, SHA-256 and size of the contents, and
+        /// time. Keys are left out unless --manifest-include-keys is given
+        #[clap(long)]
+        manifest: Option<PathBuf>,
+        /// Also store each file's key in the manifest, which then unlocks every file in it
+        #[clap(long, requires = "manifest")]
+        manifest_include_keys: bool,
+        /// Schematic files, their entries are merged and run together. `-` reads one
+        /// from stdin
+        #[clap(index = 1, required = true)]
+        filepaths: Vec<PathBuf>,
+    },
+    MakeSchematic {
+        /// Start the schematic file over instead of appending the entry to it
+        #[clap(long)]
+        overwrite: bool,
+    },
+    /// Check that a file decrypts with the given key, or for --auth-only files that it
+    /// hasn't been tampered with, without writing anything
+    Verify {
+        #[clap(short = 'k', long, required_unless_present = "secret_file")]
+        key: Option<String>,
+        #[clap(long, conflicts_with = "key")]
+        secret_file: Option<PathBuf>,
         #[clap(index = 1)]
         filepath: PathBuf,
     },
-    MakeSchematic,
+    /// Show the header of an encrypted file without decrypting it
+    Info {
+        #[clap(index = 1)]
+        filepath: PathBuf,
+    },
+    /// Encrypt a short secret typed at a prompt, such as a password or a note, and print it
+    /// as base64 along with the key. Nothing is written to disk
+    EncryptText {
+        /// Encrypt with this key instead of generating one
+        #[clap(short = 'k', long, value_parser = parse_gcm_key)]
+        key: Option<GcmKey>,
+    },
+    /// Print the secret in text from encrypt-text. The key and text are prompted for if left
+    /// out, which keeps them out of the shell history
+    DecryptText {
+        #[clap(short = 'k', long)]
+        key: Option<String>,
+        #[clap(index = 1)]
+        text: Option<String>,
+    },
+    Keygen {
+        #[clap(long, value_enum, default_value_t = Algorithm::Aes128Gcm)]
+        algorithm: Algorithm,
+        /// Number of keys to generate
+        #[clap(short = 'n', long, default_value_t = 1)]
+        count: usize,
+        /// Also print a nonce, for use with --nonce or files without a header
+        #[clap(long)]
+        with_nonce: bool,
+    },
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum KeyEncoding {
+    Hex,
+    /// Standard base64 with padding, a third shorter than hex
+    Base64,
+}
+
+impl KeyEncoding {
+    fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            KeyEncoding::Hex => hex::encode(bytes),
+            KeyEncoding::Base64 => BASE64_STANDARD.encode(bytes),
+        }
+    }
+}
+
+/// Decodes a key or nonce in either encoding. Hex is tried first, the only base64 strings that
+/// are also valid hex decode to the wrong length and are rejected by the caller.
+fn decode_key_text(text: &str) -> Option<Vec<u8>> {
+    hex::decode(text)
+        .ok()
+        .or_else(|| BASE64_STANDARD.decode(text).ok())
+}
+
+#[derive(Serialize)]
+struct ErrorReport {
+    error: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schematic: Option<String>,
+    detail: String,
+}
+
+/// Asks before `--in-place` replaces source files. Always proceeds with `--yes`. Without a
+/// terminal to ask on it exits with an error, so a script can't lose its originals by accident.
+fn confirm_replace(what: &str, yes: bool, error_format: ErrorFormat) -> bool {
+    if yes {
+        return true;
+    }
+    if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        let err = AlpacaError::NeedsConfirmation(what.to_owned());
+        exit_with_general_error(error_format, &err);
+    }
+
+    dialoguer::Confirm::new()
+        .with_prompt(format!("This will replace {}; continue?", what))
+        .default(false)
+        .interact()
+        .unwrap_or(false)
+}
+
+/// Asks for the key of one file for `decrypt --ask-each`, `None` if the user left it blank
+fn ask_key(filepath: &Path, hint: Option<&str>) -> Option<String> {
+    let prompt = match hint {
+        Some(hint) => format!("Key for \'{}\' (hint: {})", filepath.display(), hint),
+        None => format!("Key for \'{}\'", filepath.display()),
+    };
+    dialoguer::Password::new()
+        .with_prompt(prompt)
+        .allow_empty_password(true)
+        .interact()
+        .ok()
+        .filter(|key| !key.is_empty())
+}
+
+/// Size of `filepath` if it is over `max_size`, for `--max-file-size`. Files that can't be
+/// inspected are left for encrypt or decrypt to report.
+fn oversized(filepath: &Path, max_size: Option<u64>) -> Option<u64> {
+    let max_size = max_size?;
+    let size = std::fs::metadata(filepath).ok()?.len();
+    (size > max_size).then_some(size)
+}
+
+/// Whether `filepath` hasn't been modified after `since`, so `--since` skips it. Files whose
+/// modification time can't be read are never skipped.
+fn unmodified_since(filepath: &Path, since: Option<SystemTime>) -> bool {
+    let Some(since) = since else {
+        return false;
+    };
+    std::fs::metadata(filepath)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified <= since)
 }
 
+/// Prints the skip of a file over `--max-file-size`
+fn report_oversized(filepath: &Path, size: u64) {
+    log::warn!(
+        "Skipped \'{}\', {} bytes is over --max-file-size",
+        filepath.display(),
+        size
+    );
+}
+
+/// Renders `text` as a QR code on stdout for `--qr`, two modules per character cell. The text
+/// itself has already been printed, so there's nothing to fall back to when it doesn't fit.
+fn print_qr(text: &str) {
+    let term = console::Term::stdout();
+    if !term.is_term() {
+        return;
+    }
+
+    let code = qrcode::QrCode::new(text).expect("A key always fits in a QR code");
+    let rendered = code
+        .render::<qrcode::render::unicode::Dense1x2>()
+        .quiet_zone(true)
+        .build();
+    let width = rendered.lines().map(|line| line.chars().count()).max();
+    let (_, columns) = term.size();
+    if width.is_some_and(|width| width > columns as usize) {
+        log::warn!("Terminal is too narrow to show the key as a QR code");
+        return;
+    }
+    println!("{}", rendered);
+}
+
+/// Time spent on each stage of processing one file, for `--timings`. When decrypting,
+/// decompression is counted as part of the cipher stage.
+#[derive(Clone, Copy, Default)]
+struct Timings {
+    read: Duration,
+    compress: Duration,
+    cipher: Duration,
+    write: Duration,
+}
+
+impl std::ops::AddAssign for Timings {
+    fn add_assign(&mut self, other: Timings) {
+        self.read += other.read;
+        self.compress += other.compress;
+        self.cipher += other.cipher;
+        self.write += other.write;
+    }
+}
+
+impl Timings {
+    fn report(&self, what: &str) {
+        log::info!(
+            target: "timings",
+            "{}: read {:.3}s, compress {:.3}s, cipher {:.3}s, write {:.3}s",
+            what,
+            self.read.as_secs_f64(),
+            self.compress.as_secs_f64(),
+            self.cipher.as_secs_f64(),
+            self.write.as_secs_f64()
+        );
+    }
+}
+
+/// Prints elapsed time and throughput for `--verbose`
+fn report_timing(what: &str, bytes: usize, elapsed: Duration) {
+    let megabytes = bytes as f64 / 1_000_000.0;
+    let seconds = elapsed.as_secs_f64();
+    let throughput = if seconds > 0.0 {
+        megabytes / seconds
+    } else {
+        0.0
+    };
+    log::info!(
+        "{} ({:.2} MB) in {:.3}s, {:.2} MB/s",
+        what,
+        megabytes,
+        seconds,
+        throughput
+    );
+}
+
+fn exit_with_error(format: ErrorFormat, filepath: &Path, error: &AlpacaError) -> ! {
+    report_error(format, filepath, error);
+    if interrupt::requested() {
+        std::process::exit(interrupt::EXIT_STATUS);
+    }
+    std::process::exit(1);
+}
+
+fn write_manifest(manifest: &Manifest, path: &Path, error_format: ErrorFormat) {
+    manifest.write(path).unwrap_or_else(|err| {
+        let err = AlpacaError::Io("Failed to write the manifest", err);
+        exit_with_error(error_format, path, &err)
+    });
+}
+
+/// "Error" in red, unless stderr isn't a terminal or NO_COLOR is set
+fn error_label() -> console::StyledObject<&'static str> {
+    console::style("Error").red().for_stderr()
+}
+
+/// Like `exit_with_error`, for errors that aren't about any one file
+fn exit_with_general_error(format: ErrorFormat, error: &AlpacaError) -> ! {
+    match format {
+        ErrorFormat::Human => eprintln!("{}: {}", error_label(), error),
+        ErrorFormat::Json => {
+            let report = ErrorReport {
+                error: error.kind(),
+                file: None,
+                schematic: None,
+                detail: error.to_string(),
+            };
+            eprintln!(
+                "{}",
+                serde_json::to_string(&report).expect("Failed to serialize error")
+            );
+        }
+    }
+    std::process::exit(1);
+}
+
+fn report_error(format: ErrorFormat, filepath: &Path, error: &AlpacaError) {
+    report_error_from(format, None, filepath, error);
+}
+
+/// Like `report_error`, also naming the schematic file the failing entry came from
+fn report_error_from(
+    format: ErrorFormat,
+    schematic: Option<&Path>,
+    filepath: &Path,
+    error: &AlpacaError,
+) {
+    match format {
+        ErrorFormat::Human => match schematic {
+            Some(schematic) => eprintln!(
+                "{} ({}, from {}): {}",
+                error_label(),
+                filepath.display(),
+                schematic.display(),
+                error
+            ),
+            None => eprintln!("{} ({}): {}", error_label(), filepath.display(), error),
+        },
+        ErrorFormat::Json => {
+            let report = ErrorReport {
+                error: error.kind(),
+                file: Some(filepath.display().to_string()),
+                schematic: schematic.map(|schematic| schematic.display().to_string()),
+                detail: error.to_string(),
+            };
+            eprintln!(
+                "{}",
+                serde_json::to_string(&report).expect("Failed to serialize error")
+            );
+        }
+    }
+}
+
+/// Crude check that the OS RNG isn't handing out zeros or repeating itself. It can't prove the
+/// output is random, only catch an RNG that is plainly broken before keys are made with it.
+fn check_rng() -> Result<(), AlpacaError> {
+    let mut first = [0u8; 32];
+    let mut second = [0u8; 32];
+    OsRng
+        .try_fill_bytes(&mut first)
+        .and_then(|_| OsRng.try_fill_bytes(&mut second))
+        .map_err(|_| AlpacaError::BrokenRng("The operating system RNG failed"))?;
+
+    if first.iter().all(|&byte| byte == first[0]) {
+        return Err(AlpacaError::BrokenRng(
+            "The operating system RNG returned a constant sample",
+        ));
+    }
+    if first == second {
+        return Err(AlpacaError::BrokenRng(
+            "The operating system RNG returned the same sample twice",
+        ));
+    }
+    Ok(())
+}
+
+/// Length of the nonce AES-GCM is used with
+const GCM_NONCE_LEN: usize = 12;
+/// Length of the authentication tag AES-GCM appends to the ciphertext
+const GCM_TAG_LEN: usize = 16;
+/// Smallest possible gzip stream: 10 byte header, an empty deflate block and 8 byte trailer
+const GZIP_MIN_LEN: usize = 20;
+
 enum GzipMode {
     Compress,
     Decompress,
 }
 
-fn gzip(input: &[u8], mode: GzipMode) -> Vec<u8> {
+fn gzip(input: &[u8], mode: GzipMode) -> Result<Vec<u8>, AlpacaError> {
     match mode {
         GzipMode::Compress => {
             let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
-            encoder.write_all(input).unwrap();
-            encoder.finish().unwrap()
+            encoder
+                .write_all(input)
+                .map_err(|err| AlpacaError::Io("Failed to compress data", err))?;
+            encoder
+                .finish()
+                .map_err(|err| AlpacaError::Io("Failed to compress data", err))
         }
         GzipMode::Decompress => {
             let mut decoder = GzDecoder::new(input);
             let mut decompressed_data = Vec::new();
-            decoder.read_to_end(&mut decompressed_data).unwrap();
-            decompressed_data
+            decoder
+                .read_to_end(&mut decompressed_data)
+                .map_err(AlpacaError::Corrupt)?;
+            Ok(decompressed_data)
         }
     }
 }
@@ -95,201 +720,2374 @@ type GcmNonce = aes_gcm::aead::generic_array::GenericArray<
     >,
 >;
 
-fn encrypt(filepath: &PathBuf, key: &GcmKey, nonce: &GcmNonce) {
+/// Splits a `key` or `key#nonce` string. The nonce is only needed for files without a header.
+fn parse_key(key: &str) -> Result<(Vec<u8>, Option<Vec<u8>>), AlpacaError> {
+    let (key, nonce) = match key.split_once('#') {
+        Some((key, nonce)) => (key, Some(nonce)),
+        None => (key, None),
+    };
+    let key = decode_key_text(key).ok_or(AlpacaError::MalformedKey("Malformed key"))?;
+    let nonce = nonce
+        .map(|nonce| {
+            decode_key_text(nonce).ok_or(AlpacaError::MalformedKey("Malformed key(nonce)"))
+        })
+        .transpose()?;
+
+    Ok((key, nonce))
+}
+
+fn parse_gcm_key(key: &str) -> Result<GcmKey, AlpacaError> {
+    let key = decode_key_text(key.trim()).ok_or(AlpacaError::MalformedKey("Malformed key"))?;
+    GcmKey::from_exact_iter(key).ok_or(AlpacaError::MalformedKey("Key must be 16 bytes long"))
+}
+
+fn parse_hint(hint: &str) -> Result<String, String> {
+    if hint.len() > header::MAX_HINT_LEN {
+        return Err(format!(
+            "Hint can be at most {} bytes long",
+            header::MAX_HINT_LEN
+        ));
+    }
+
+    Ok(hint.to_owned())
+}
+
+fn parse_gcm_nonce(nonce: &str) -> Result<GcmNonce, AlpacaError> {
+    let nonce =
+        decode_key_text(nonce.trim()).ok_or(AlpacaError::MalformedKey("Malformed nonce"))?;
+    GcmNonce::from_exact_iter(nonce).ok_or(AlpacaError::MalformedKey("Nonce must be 12 bytes long"))
+}
+
+/// Length of the random salt stored with keys derived from a secret file
+const SALT_LEN: usize = 16;
+
+/// Reads a secret file for `--secret-file`. The bytes are wiped from memory once dropped.
+fn read_secret(filepath: &Path) -> Result<Zeroizing<Vec<u8>>, AlpacaError> {
+    let secret = Zeroizing::new(
+        std::fs::read(filepath)
+            .map_err(|err| AlpacaError::Io("Failed to read secret file", err))?,
+    );
+    if secret.is_empty() {
+        return Err(AlpacaError::MalformedKey("Secret file is empty"));
+    }
+    Ok(secret)
+}
+
+/// Derives an AES key from the contents of a secret file with HKDF-SHA256
+fn derive_key(secret: &[u8], salt: &[u8]) -> GcmKey {
+    let mut okm = Zeroizing::new([0u8; 16]);
+    Hkdf::<Sha256>::new(Some(salt), secret)
+        .expand(b"alpaca secret-file key", okm.as_mut())
+        .expect("16 bytes is a valid HKDF-SHA256 output length");
+    GcmKey::clone_from_slice(okm.as_ref())
+}
+
+/// Below this size gzip's own framing usually outweighs what it saves
+const DEFAULT_COMPRESS_MIN_SIZE: usize = 256;
+/// Compressed data has to come out at most this fraction of the original to be kept
+const DEFAULT_MIN_COMPRESSION_RATIO: f64 = 0.98;
+
+/// Settings for `encrypt` that are the same for every file in a run
+#[derive(Clone)]
+struct EncryptOptions {
+    hint: Option<String>,
+    /// Store the SHA-256 of the plaintext in the header
+    checksum: bool,
+    in_place: bool,
+    /// Decrypt the written file and compare it to the input before going on
+    verify: bool,
+    /// Mirror the inputs under this directory instead of writing next to them
+    output_dir: Option<PathBuf>,
+    /// Where output is written before it's renamed into place
+    temp_dir: Option<PathBuf>,
+    /// Names the encrypted file instead of appending `.alp`
+    name_template: Option<String>,
+    /// Write the encrypted file to exactly this path, set per schematic entry
+    output: Option<PathBuf>,
+    /// Hide the file name in the header and name the encrypted file after a hash
+    hash_name: bool,
+    /// Inputs smaller than this are stored uncompressed
+    compress_min_size: usize,
+    /// Compressed data larger than this fraction of the input is thrown away
+    min_compression_ratio: f64,
+    /// Write `nonce || ciphertext` without a header
+    raw: bool,
+    /// Leave the plaintext readable and only authenticate it
+    auth_only: bool,
+    /// Keys the file's key is wrapped with, one per recipient
+    recipients: Vec<GcmKey>,
+    /// Use this codec instead of picking one by size and ratio, set per schematic entry
+    codec: Option<Codec>,
+}
+
+impl Default for EncryptOptions {
+    fn default() -> Self {
+        EncryptOptions {
+            hint: None,
+            checksum: false,
+            in_place: false,
+            verify: false,
+            output_dir: None,
+            temp_dir: None,
+            name_template: None,
+            output: None,
+            hash_name: false,
+            compress_min_size: DEFAULT_COMPRESS_MIN_SIZE,
+            min_compression_ratio: DEFAULT_MIN_COMPRESSION_RATIO,
+            raw: false,
+            auth_only: false,
+            recipients: Vec::new(),
+            codec: None,
+        }
+    }
+}
+
+/// Compresses `input` if that pays off, returning the codec that was picked
+fn compress_input<'a>(
+    input: &'a [u8],
+    options: &EncryptOptions,
+) -> Result<(Codec, Cow<'a, [u8]>), AlpacaError> {
+    let uncompressed = Ok((Codec::None, Cow::Borrowed(input)));
+    if options.auth_only || options.codec == Some(Codec::None) {
+        return uncompressed;
+    }
+    if options.codec == Some(Codec::Gzip) {
+        return Ok((Codec::Gzip, Cow::Owned(gzip(input, GzipMode::Compress)?)));
+    }
+    // Raw files can't record the codec, so they are always gzipped, even when empty. Files
+    // with a header never compress empty input, gzip would turn it into 20 bytes of framing.
+    let skip_compression = input.is_empty() || input.len() < options.compress_min_size;
+    if !options.raw && skip_compression {
+        return uncompressed;
+    }
+    let compressed = gzip(input, GzipMode::Compress)?;
+    // Data that barely shrinks isn't worth decompressing on every read
+    let ratio = compressed.len() as f64 / input.len().max(1) as f64;
+    if !options.raw && ratio > options.min_compression_ratio {
+        return uncompressed;
+    }
+    Ok((Codec::Gzip, Cow::Owned(compressed)))
+}
+
+/// What `encrypt --dry-run` reports: where the encrypted copy of `filepath` would go and how
+/// it would be compressed. Reads and compresses the input, but writes nothing.
+fn plan_encrypt(
+    filepath: &Path,
+    options: &EncryptOptions,
+) -> Result<(PathBuf, Codec), AlpacaError> {
+    let input =
+        std::fs::read(filepath).map_err(|err| AlpacaError::Io("Error reading input file", err))?;
+    let (codec, _) = compress_input(&input, options)?;
+    let mut newpath = encrypted_path(filepath, &input, options)?;
+    if options.hash_name {
+        // The real name depends on the key, which a dry run doesn't generate
+        newpath.set_file_name("<hash>.alp");
+    }
+    Ok((newpath, codec))
+}
+
+/// A file `encrypt` wrote
+struct Encrypted {
+    /// Size of the plaintext in bytes
+    size: usize,
+    path: PathBuf,
+    codec: Codec,
+    sha256: [u8; 32],
+}
+
+/// Returns what was written and how long each stage took. `salt` is stored in the header when
+/// the key was derived from a secret file.
+fn encrypt(
+    filepath: &PathBuf,
+    key: &GcmKey,
+    nonce: &GcmNonce,
+    salt: Option<&[u8]>,
+    options: &EncryptOptions,
+) -> Result<(Encrypted, Timings), AlpacaError> {
     let cipher = Aes128Gcm::new(key);
+    let mut timings = Timings::default();
+
+    let stage = Instant::now();
+    let input =
+        std::fs::read(filepath).map_err(|err| AlpacaError::Io("Error reading input file", err))?;
+    timings.read = stage.elapsed();
+    if input.is_empty() {
+        log::warn!(
+            "\'{}\' is empty, the encrypted file will only contain overhead",
+            filepath.display()
+        );
+    }
+    let stage = Instant::now();
+    let (codec, compressed) = compress_input(&input, options)?;
+    timings.compress = stage.elapsed();
+
+    let sha256: [u8; 32] = Sha256::digest(&input).into();
+    let wrapped_keys = options
+        .recipients
+        .iter()
+        .map(|recipient| {
+            Aes128Gcm::new(recipient)
+                .encrypt(nonce, key.as_slice())
+                .map_err(|_| AlpacaError::EncryptionFailed)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // A templated name is no way back to the original, so the original goes in the header
+    let name = if options.hash_name || (options.name_template.is_some() && !options.raw) {
+        Some(encrypt_name(&cipher, nonce, filepath)?)
+    } else {
+        None
+    };
+    let header = if options.raw {
+        Vec::new()
+    } else {
+        Header {
+            version: header::VERSION,
+            algorithm: Algorithm::Aes128Gcm,
+            codec,
+            nonce: nonce.to_vec(),
+            hint: options.hint.clone(),
+            sha256: options.checksum.then_some(sha256),
+            salt: salt.map(<[u8]>::to_vec),
+            auth_only: options.auth_only,
+            name: name.clone(),
+            wrapped_keys,
+        }
+        .to_bytes()?
+    };
+
+    let ciphertext = if options.auth_only {
+        let stage = Instant::now();
+        let tag = cipher
+            .encrypt(
+                nonce,
+                Payload {
+                    msg: &[],
+                    aad: &[header.as_slice(), &input].concat(),
+                },
+            )
+            .map_err(|_| AlpacaError::EncryptionFailed)?;
+        timings.cipher = stage.elapsed();
+        [input.as_slice(), &tag].concat()
+    } else {
+        let stage = Instant::now();
+        let ciphertext = cipher
+            .encrypt(
+                nonce,
+                Payload {
+                    msg: &compressed,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| AlpacaError::EncryptionFailed)?;
+        timings.cipher = stage.elapsed();
+        ciphertext
+    };
+    let mut output = header;
+    if options.raw {
+        output.extend_from_slice(nonce);
+    }
+    output.extend_from_slice(&ciphertext);
+
+    let stage = Instant::now();
+    let mut newpath = encrypted_path(filepath, &input, options)?;
+    if let Some(name) = &name {
+        newpath.set_file_name(hashed_name(name));
+    }
+    if options.output_dir.is_some() {
+        if let Some(parent) = newpath.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|err| AlpacaError::Io("Failed to create output directory", err))?;
+        }
+    }
+    atomic::write(&newpath, &output, filepath, options.temp_dir.as_deref())
+        .map_err(|err| AlpacaError::Io("Failed to write encrypted data", err))?;
+    attributes::copy(filepath, &newpath)
+        .map_err(|err| AlpacaError::Io("Failed to copy file attributes", err))?;
+    timings.write = stage.elapsed();
+
+    if options.verify {
+        let written = std::fs::read(&newpath)
+            .map_err(|err| AlpacaError::Io("Failed to read back encrypted data", err))?;
+        let raw = options.raw.then_some((Algorithm::Aes128Gcm, codec));
+        match decrypt_data(&cipher, &written, None, raw) {
+            Ok(roundtrip) if roundtrip == input => {}
+            _ => {
+                // Leaving it around would only invite someone to trust it
+                let _ = std::fs::remove_file(&newpath);
+                return Err(AlpacaError::VerificationFailed);
+            }
+        }
+    }
+
+    if options.in_place {
+        std::fs::remove_file(filepath)
+            .map_err(|err| AlpacaError::Io("Failed to remove the original file", err))?;
+    }
+
+    let encrypted = Encrypted {
+        size: input.len(),
+        path: newpath,
+        codec,
+        sha256,
+    };
+    Ok((encrypted, timings))
+}
+
+/// The `.alp` files directly inside `dir`, sorted by name
+fn encrypted_files_in(dir: &Path) -> Result<Vec<PathBuf>, AlpacaError> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|err| AlpacaError::Io("Failed to read directory", err))?;
+    let mut filepaths = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|err| AlpacaError::Io("Failed to read directory", err))?
+            .path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "alp") {
+            filepaths.push(path);
+        }
+    }
+    filepaths.sort();
+    Ok(filepaths)
+}
+
+/// Path `encrypt` writes the encrypted copy of `filepath`, whose contents are `input`, to
+fn encrypted_path(
+    filepath: &Path,
+    input: &[u8],
+    options: &EncryptOptions,
+) -> Result<PathBuf, AlpacaError> {
+    if let Some(output) = &options.output {
+        return Ok(output.clone());
+    }
+
+    let target = match &options.output_dir {
+        Some(dir) => mirrored_path(dir, filepath)?,
+        None => filepath.to_path_buf(),
+    };
+
+    if let Some(template) = &options.name_template {
+        let target = target.with_file_name(render_name(template, filepath, input));
+        if target == filepath {
+            return Err(AlpacaError::InvalidPath(
+                "Name template gives the encrypted file the same name as the original",
+            ));
+        }
+        return Ok(target);
+    }
+
+    Ok(with_alp_suffix(target))
+}
+
+/// Appends `.alp` to the file name, keeping any bytes that aren't valid UTF-8
+fn with_alp_suffix(filepath: PathBuf) -> PathBuf {
+    let mut name = filepath.into_os_string();
+    name.push(".alp");
+    PathBuf::from(name)
+}
+
+/// Nonce the file name is encrypted with under `--hash-name`. Derived from the file's own
+/// nonce, so it's as unique as that one without ever being equal to it.
+fn name_nonce(nonce: &[u8]) -> GcmNonce {
+    let digest = Sha256::new()
+        .chain_update(b"alpaca file name")
+        .chain_update(nonce)
+        .finalize();
+    GcmNonce::clone_from_slice(&digest[..12])
+}
+
+/// The bytes a file name is stored as. On Unix these are the name's own bytes, whatever their
+/// encoding, elsewhere names are stored as UTF-8.
+#[cfg(unix)]
+fn name_to_bytes(name: &OsStr) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(name.as_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+fn name_to_bytes(name: &OsStr) -> Option<Vec<u8>> {
+    name.to_str().map(|name| name.as_bytes().to_vec())
+}
+
+#[cfg(unix)]
+fn name_from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn name_from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    String::from_utf8(bytes).ok().map(OsString::from)
+}
+
+/// Encrypts the file name of `filepath` for the header of a `--hash-name` file
+fn encrypt_name(
+    cipher: &Aes128Gcm,
+    nonce: &GcmNonce,
+    filepath: &Path,
+) -> Result<Vec<u8>, AlpacaError> {
+    let name = filepath
+        .file_name()
+        .and_then(name_to_bytes)
+        .ok_or(AlpacaError::InvalidPath(
+            "File name can't be stored by --hash-name on this system",
+        ))?;
+    cipher
+        .encrypt(&name_nonce(nonce), name.as_slice())
+        .map_err(|_| AlpacaError::EncryptionFailed)
+}
+
+/// Reverses `encrypt_name`. The name has to be a plain file name, so a crafted header can't
+/// send the plaintext to another directory.
+fn decrypt_name(
+    cipher: &Aes128Gcm,
+    nonce: &[u8],
+    encrypted: &[u8],
+) -> Result<OsString, AlpacaError> {
+    let name = cipher
+        .decrypt(&name_nonce(nonce), encrypted)
+        .map_err(|_| AlpacaError::AuthenticationFailed)?;
+    let name = name_from_bytes(name).ok_or(AlpacaError::InvalidHeader(
+        "Stored file name can't be used on this system",
+    ))?;
+    let mut components = Path::new(&name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(name),
+        _ => Err(AlpacaError::InvalidHeader(
+            "Stored file name is not a plain file name",
+        )),
+    }
+}
+
+/// Name of a `--hash-name` file. Hashing the encrypted name rather than the name itself means
+/// names can't be guessed and checked without the key, and the same file gets a new name
+/// every time it's encrypted.
+fn hashed_name(encrypted_name: &[u8]) -> String {
+    format!("{}.alp", &hex::encode(Sha256::digest(encrypted_name))[..32])
+}
+
+/// Tokens `--name-template` knows, the rest of the template is copied as is
+const NAME_TOKENS: [&str; 4] = ["stem", "ext", "date", "hash"];
+
+fn parse_name_template(template: &str) -> Result<String, String> {
+    if template.is_empty() || template.contains(['/', '\\']) {
+        return Err("Name template must be a file name, not a path".to_owned());
+    }
+    let unmatched = "Name template has a '}' without an opening '{'";
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if rest[..start].contains('}') {
+            return Err(unmatched.to_owned());
+        }
+        let len = rest[start..]
+            .find('}')
+            .ok_or("Name template has a '{' without a closing '}'")?;
+        let token = &rest[start + 1..start + len];
+        if !NAME_TOKENS.contains(&token) {
+            return Err(format!(
+                "Unknown token '{{{}}}', expected one of {{stem}}, {{ext}}, {{date}} or {{hash}}",
+                token
+            ));
+        }
+        rest = &rest[start + len + 1..];
+    }
+    if rest.contains('}') {
+        return Err(unmatched.to_owned());
+    }
+    Ok(template.to_owned())
+}
+
+/// Fills in a template checked by `parse_name_template`. `{date}` is today's UTC date and
+/// `{hash}` the first 16 hex digits of the plaintext's SHA-256.
+fn render_name(template: &str, filepath: &Path, input: &[u8]) -> OsString {
+    let mut name = OsString::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start
+            + rest[start..]
+                .find('}')
+                .expect("parse_name_template checked that every '{' is closed");
+        name.push(&rest[..start]);
+        match &rest[start + 1..end] {
+            "stem" => name.push(filepath.file_stem().unwrap_or_default()),
+            "ext" => name.push(filepath.extension().unwrap_or_default()),
+            "date" => name.push(utc_date(SystemTime::now())),
+            "hash" => name.push(&hex::encode(Sha256::digest(input))[..16]),
+            token => unreachable!("unknown token {{{}}} got past parse_name_template", token),
+        }
+        rest = &rest[end + 1..];
+    }
+    name.push(rest);
+    name
+}
+
+/// Reads `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS`, in UTC, for `--since`
+fn parse_since(value: &str) -> Result<SystemTime, String> {
+    let invalid = || {
+        format!(
+            "'{}' is not a date like 2024-05-01 or 2024-05-01T18:30:00",
+            value
+        )
+    };
+    let (date, time) = value.split_once(['T', ' ']).unwrap_or((value, "00:00:00"));
+    let numbers = |text: &str, separator| -> Result<Vec<i64>, String> {
+        text.split(separator)
+            .map(|part| part.parse().map_err(|_| invalid()))
+            .collect()
+    };
+    let (&[year, month, day], &[hour, minute, second]) = (
+        numbers(date, '-')?.as_slice(),
+        numbers(time, ':')?.as_slice(),
+    ) else {
+        return Err(invalid());
+    };
+    if !(1..=12).contains(&month) || hour > 23 || minute > 59 || second > 59 {
+        return Err(invalid());
+    }
+
+    // The inverse of `utc_date`, from the same algorithms
+    let shifted_year = year - i64::from(month <= 2);
+    let era = shifted_year.div_euclid(400);
+    let year_of_era = shifted_year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let secs = u64::try_from(days * 86_400 + hour * 3_600 + minute * 60 + second)
+        .map_err(|_| format!("'{}' is before 1970", value))?;
+    let time = UNIX_EPOCH + Duration::from_secs(secs);
+    // Catches days past the end of their month, e.g. 2023-02-30
+    if utc_date(time) != format!("{:04}-{:02}-{:02}", year, month, day) {
+        return Err(invalid());
+    }
+    Ok(time)
+}
+
+/// `time` as a `YYYY-MM-DD` date in UTC
+fn utc_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    // Days since 1970-01-01 to a civil date, from Howard Hinnant's date algorithms
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Whether `filepath` still matches its entry in the manifest of an earlier run, for
+/// `--dedup`. Anything that gets in the way of telling counts as changed.
+fn unchanged_since_encrypted(filepath: &Path, manifest: &Manifest) -> bool {
+    let Ok(input) = std::fs::read(filepath) else {
+        return false;
+    };
+    manifest.carry_over(filepath, &Sha256::digest(&input).into())
+}
+
+/// Where `filepath` goes under `dir`. The root and `.` components are dropped, so absolute and
+/// relative inputs both land inside `dir`.
+fn mirrored_path(dir: &Path, filepath: &Path) -> Result<PathBuf, AlpacaError> {
+    let mut path = dir.to_path_buf();
+    for component in filepath.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::ParentDir => {
+                return Err(AlpacaError::InvalidPath(
+                    "Paths containing '..' can't be mirrored under an output directory",
+                ))
+            }
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    Ok(path)
+}
+
+/// Runs `command` with `{file}` replaced by `filepath` in every argument and writes the key to
+/// its stdin, so the key never shows up on stdout or on disk.
+fn run_key_exec(command: &[String], filepath: &Path, key: &str) -> Result<(), AlpacaError> {
+    // Built as OsStrings so a file name that isn't valid UTF-8 reaches the command intact
+    let args: Vec<OsString> = command
+        .iter()
+        .map(|arg| {
+            let mut expanded = OsString::new();
+            for (index, part) in arg.split("{file}").enumerate() {
+                if index > 0 {
+                    expanded.push(filepath);
+                }
+                expanded.push(part);
+            }
+            expanded
+        })
+        .collect();
 
-    let input = std::fs::read(filepath).expect("Error reading input file");
-    let output = cipher
-        .encrypt(nonce, input.as_ref())
-        .expect("Failed to encrypt");
+    let mut child = std::process::Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| AlpacaError::Io("Failed to run the key command", err))?;
+    let mut stdin = child.stdin.take().expect("Child stdin is piped");
+    writeln!(stdin, "{}", key)
+        .map_err(|err| AlpacaError::Io("Failed to pass the key to the key command", err))?;
+    drop(stdin);
 
-    let output = gzip(&output, GzipMode::Compress);
+    let status = child
+        .wait()
+        .map_err(|err| AlpacaError::Io("Failed to wait for the key command", err))?;
+    if !status.success() {
+        return Err(AlpacaError::KeyExecFailed(status));
+    }
+    Ok(())
+}
+
+/// Settings for `decrypt` that are the same for every file in a run
+#[derive(Clone, Default)]
+struct DecryptOptions {
+    in_place: bool,
+    /// Write the plaintext to stdout instead of a file
+    stdout: bool,
+    format: FormatFlags,
+    /// Where output is written before it's renamed into place
+    temp_dir: Option<PathBuf>,
+    naming: DecryptNaming,
+}
+
+/// How `decrypt` names the file it writes
+#[derive(Clone, Default)]
+enum DecryptNaming {
+    /// `report.pdf.alp` becomes `report.pdf`
+    #[default]
+    StripAlp,
+    /// `report.pdf.alp` becomes `report.pdf` plus the suffix, e.g. `report.pdf.dec`
+    AppendSuffix(String),
+    /// Always write to this path
+    Exact(PathBuf),
+}
+
+/// Where the plaintext of `filepath` goes. `None` when it doesn't end in `.alp` and no other
+/// name was asked for, in which case nothing is written.
+fn decrypted_path(filepath: &Path, naming: &DecryptNaming) -> Option<PathBuf> {
+    let stripped = match filepath.extension() {
+        Some(ext) if ext == "alp" => Some(filepath.with_extension("")),
+        _ => None,
+    };
+    match naming {
+        DecryptNaming::StripAlp => stripped,
+        DecryptNaming::AppendSuffix(suffix) => {
+            let mut name = stripped
+                .unwrap_or_else(|| filepath.to_path_buf())
+                .into_os_string();
+            name.push(suffix);
+            Some(PathBuf::from(name))
+        }
+        DecryptNaming::Exact(path) => Some(path.clone()),
+    }
+}
+
+/// Algorithm and codec given to `decrypt` on the command line
+#[derive(Clone, Copy, Default)]
+struct FormatFlags {
+    /// The file has no header, so `algorithm` and `codec` are the only source for them
+    raw: bool,
+    /// The file is from 0.1, gzip over GCM without a header. Normally detected on its own
+    legacy: bool,
+    algorithm: Option<Algorithm>,
+    codec: Option<Codec>,
+}
+
+impl FormatFlags {
+    /// Files with a header describe themselves, flags given anyway only have to agree with it
+    fn check(&self, header: &Header) -> Result<(), AlpacaError> {
+        if let Some(algorithm) = self.algorithm.filter(|&a| a != header.algorithm) {
+            return Err(AlpacaError::HeaderMismatch {
+                field: "Algorithm",
+                given: algorithm.to_string(),
+                found: header.algorithm.to_string(),
+            });
+        }
+        if let Some(codec) = self.codec.filter(|&c| c != header.codec) {
+            return Err(AlpacaError::HeaderMismatch {
+                field: "Compression",
+                given: codec.to_string(),
+                found: header.codec.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// What `decrypt` opens a file with
+enum DecryptKey {
+    /// A key, or `key#nonce` for legacy files, as printed by encrypt
+    Text(String),
+    /// The contents of the secret file the key was derived from
+    Secret(Zeroizing<Vec<u8>>),
+}
+
+impl DecryptKey {
+    /// The key itself when it was given directly, for the `--log-file` fingerprint
+    fn given_key(&self) -> Option<Vec<u8>> {
+        match self {
+            DecryptKey::Text(key) => parse_key(key).ok().map(|(key, _)| key),
+            DecryptKey::Secret(_) => None,
+        }
+    }
+}
+
+/// Picks the key from `--key` or `--secret-file`, one of which clap makes sure is given
+fn decrypt_key(
+    key: Option<String>,
+    secret_file: Option<PathBuf>,
+    error_format: ErrorFormat,
+) -> DecryptKey {
+    match secret_file {
+        Some(secret_file) => DecryptKey::Secret(
+            read_secret(&secret_file)
+                .unwrap_or_else(|err| exit_with_error(error_format, &secret_file, &err)),
+        ),
+        None => DecryptKey::Text(key.expect("clap requires --key or --secret-file")),
+    }
+}
+
+/// Sets up the cipher for `input`, along with the nonce for legacy files if one was given
+fn open_cipher(
+    key: &DecryptKey,
+    input: &[u8],
+) -> Result<(Aes128Gcm, Option<Vec<u8>>), AlpacaError> {
+    let (key, nonce) = match key {
+        DecryptKey::Text(key) => parse_key(key)?,
+        DecryptKey::Secret(secret) => {
+            let salt = Header::parse(input)?
+                .and_then(|(header, _)| header.salt)
+                .ok_or(AlpacaError::MalformedKey(
+                    "File was not encrypted with a secret file",
+                ))?;
+            (derive_key(secret, &salt).to_vec(), None)
+        }
+    };
+
+    let cipher = Aes128Gcm::new_from_slice(&key)
+        .map_err(|_| AlpacaError::MalformedKey("Key must be 16 bytes long"))?;
+    // A file encrypted for recipients is opened by whichever wrapped key this one unwraps
+    let header = Header::parse(input).ok().flatten();
+    match header {
+        Some((header, _)) if !header.wrapped_keys.is_empty() => {
+            let wrap_nonce = GcmNonce::from_exact_iter(header.nonce)
+                .ok_or(AlpacaError::InvalidHeader("Nonce must be 12 bytes long"))?;
+            let file_key = header
+                .wrapped_keys
+                .iter()
+                .find_map(|wrapped| cipher.decrypt(&wrap_nonce, wrapped.as_slice()).ok())
+                .map(Zeroizing::new)
+                .ok_or(AlpacaError::AuthenticationFailed)?;
+            let cipher = Aes128Gcm::new_from_slice(&file_key)
+                .map_err(|_| AlpacaError::InvalidHeader("Wrapped key must be 16 bytes long"))?;
+            Ok((cipher, nonce))
+        }
+        _ => Ok((cipher, nonce)),
+    }
+}
 
-    let previous_extension = filepath.extension();
+/// Checks that `filepath` authenticates under `key` without writing anything
+fn verify(filepath: &Path, key: &DecryptKey) -> Result<(), AlpacaError> {
+    let input =
+        std::fs::read(filepath).map_err(|err| AlpacaError::Io("Error reading input file", err))?;
+    let (cipher, nonce) = open_cipher(key, &input)?;
+    decrypt_data(&cipher, &input, nonce, None)?;
+    Ok(())
+}
+
+/// Encrypts a secret for `encrypt-text` the way a file is encrypted, header included, and
+/// base64 encodes the result so it can be pasted anywhere
+fn encrypt_text(text: &str, key: &GcmKey, nonce: &GcmNonce) -> Result<String, AlpacaError> {
+    let header = Header {
+        version: header::VERSION,
+        algorithm: Algorithm::Aes128Gcm,
+        codec: Codec::None,
+        nonce: nonce.to_vec(),
+        hint: None,
+        sha256: None,
+        salt: None,
+        auth_only: false,
+        name: None,
+        wrapped_keys: Vec::new(),
+    }
+    .to_bytes()?;
+    let ciphertext = Aes128Gcm::new(key)
+        .encrypt(
+            nonce,
+            Payload {
+                msg: text.as_bytes(),
+                aad: &header,
+            },
+        )
+        .map_err(|_| AlpacaError::EncryptionFailed)?;
+    Ok(BASE64_STANDARD.encode([header, ciphertext].concat()))
+}
+
+/// Reverses `encrypt_text`
+fn decrypt_text(text: &str, key: &str) -> Result<Zeroizing<String>, AlpacaError> {
+    let input = BASE64_STANDARD
+        .decode(text.trim())
+        .map_err(|_| AlpacaError::MalformedText("Text is not valid base64"))?;
+    if Header::parse(&input)?.is_none() {
+        return Err(AlpacaError::MalformedText(
+            "Text was not made by encrypt-text",
+        ));
+    }
+    let (cipher, _) = open_cipher(&DecryptKey::Text(key.trim().to_owned()), &input)?;
+    let plainbytes = Zeroizing::new(decrypt_data(&cipher, &input, None, None)?);
+    Ok(Zeroizing::new(
+        String::from_utf8_lossy(&plainbytes).into_owned(),
+    ))
+}
+
+/// `decrypt` accepts the name of the plaintext for the encrypted file next to it
+fn encrypted_input(filepath: PathBuf) -> PathBuf {
+    if filepath.exists() {
+        filepath
+    } else {
+        with_alp_suffix(filepath)
+    }
+}
+
+/// Where `decrypt` writes the plaintext of `filepath`, whose contents are `input`. A
+/// `--hash-name` file decrypts to the name it had, in the directory it's in now.
+fn decrypted_target(
+    filepath: &Path,
+    input: &[u8],
+    cipher: &Aes128Gcm,
+    options: &DecryptOptions,
+) -> Result<PathBuf, AlpacaError> {
+    let stored_name = if options.format.raw || options.format.legacy {
+        None
+    } else {
+        Header::parse(input)?.and_then(|(header, _)| header.name.map(|name| (header.nonce, name)))
+    };
+    let named = match stored_name {
+        Some((nonce, name)) => {
+            let mut name = decrypt_name(cipher, &nonce, &name)?;
+            name.push(".alp");
+            filepath.with_file_name(name)
+        }
+        None => filepath.to_path_buf(),
+    };
+    match decrypted_path(&named, &options.naming) {
+        // Without an extension to strip, only --in-place writes over the encrypted file
+        None if options.in_place && named.extension().is_none() => Ok(named),
+        Some(newpath) => Ok(newpath),
+        None => Err(AlpacaError::InvalidPath(
+            "Name doesn't end in .alp, pass -o or --suffix to choose where to decrypt it to",
+        )),
+    }
+}
+
+/// What `decrypt --dry-run` reports: the file that would be read, where its plaintext would
+/// go and how the file says it was made. Checks the key and header without decrypting.
+fn plan_decrypt(
+    filepath: PathBuf,
+    key: &DecryptKey,
+    options: &DecryptOptions,
+) -> Result<(PathBuf, Option<PathBuf>, String), AlpacaError> {
+    let format = options.format;
+    let filepath = encrypted_input(filepath);
+    let input =
+        std::fs::read(&filepath).map_err(|err| AlpacaError::Io("Error reading input file", err))?;
+    let (cipher, nonce) = open_cipher(key, &input)?;
+
+    let header = if format.raw || format.legacy {
+        None
+    } else {
+        Header::parse(&input)?
+    };
+    let description = match (header, format.algorithm.zip(format.codec)) {
+        (_, Some((algorithm, codec))) if format.raw => {
+            format!("{}, compression: {}, no header", algorithm, codec)
+        }
+        (Some((header, _)), _) => {
+            format.check(&header)?;
+            format!("{}, compression: {}", header.algorithm, header.codec)
+        }
+        _ => {
+            if nonce.is_none() {
+                return Err(AlpacaError::MalformedKey(
+                    "Files without a header need the nonce part of the key (key#nonce), \
+                     or --raw if they were written with --no-header",
+                ));
+            }
+            format!("{}, compression: gzip, 0.1 format", Algorithm::Aes128Gcm)
+        }
+    };
+
+    let target = if options.stdout {
+        None
+    } else {
+        Some(decrypted_target(&filepath, &input, &cipher, options)?)
+    };
+    Ok((filepath, target, description))
+}
+
+/// Returns the size of the encrypted input in bytes and how long each stage took
+fn decrypt(
+    filepath: PathBuf,
+    key: &DecryptKey,
+    options: &DecryptOptions,
+) -> Result<(usize, Timings), AlpacaError> {
+    let format = options.format;
+    let filepath = encrypted_input(filepath);
+
+    let mut timings = Timings::default();
+    let stage = Instant::now();
+    let input =
+        std::fs::read(&filepath).map_err(|err| AlpacaError::Io("Error reading input file", err))?;
+    let input_len = input.len();
+    timings.read = stage.elapsed();
+    let (cipher, nonce) = open_cipher(key, &input)?;
+
+    let raw = if format.raw {
+        format.algorithm.zip(format.codec)
+    } else if format.legacy {
+        None
+    } else {
+        if let Some((header, _)) = Header::parse(&input)? {
+            format.check(&header)?;
+        }
+        None
+    };
+    let stage = Instant::now();
+    let plainbytes = if format.legacy {
+        let nonce = nonce.ok_or(AlpacaError::MalformedKey(
+            "--legacy needs the key#nonce string the file was encrypted with",
+        ))?;
+        decrypt_legacy(&cipher, &nonce, &input)?
+    } else {
+        decrypt_data(&cipher, &input, nonce, raw)?
+    };
+    timings.cipher = stage.elapsed();
+
+    let stage = Instant::now();
+    if options.stdout {
+        std::io::stdout()
+            .lock()
+            .write_all(&plainbytes)
+            .map_err(|err| AlpacaError::Io("Failed to write decrypted data to stdout", err))?;
+        timings.write = stage.elapsed();
+        return Ok((input_len, timings));
+    }
+
+    let newpath = decrypted_target(&filepath, &input, &cipher, options)?;
+    atomic::write(
+        &newpath,
+        &plainbytes,
+        &filepath,
+        options.temp_dir.as_deref(),
+    )
+    .map_err(|err| AlpacaError::Io("Failed to write decrypted data", err))?;
+    if newpath != filepath {
+        attributes::copy(&filepath, &newpath)
+            .map_err(|err| AlpacaError::Io("Failed to copy file attributes", err))?;
+
+        if options.in_place {
+            std::fs::remove_file(filepath)
+                .map_err(|err| AlpacaError::Io("Failed to remove the original file", err))?;
+        }
+    }
+    timings.write = stage.elapsed();
+
+    Ok((input_len, timings))
+}
+
+/// Decrypts the contents of an encrypted file in whichever format it was written. `nonce` is only
+/// used for legacy files, `raw` is the algorithm and codec of a file without a header.
+fn decrypt_data(
+    cipher: &Aes128Gcm,
+    input: &[u8],
+    nonce: Option<Vec<u8>>,
+    raw: Option<(Algorithm, Codec)>,
+) -> Result<Vec<u8>, AlpacaError> {
+    match raw {
+        Some((algorithm, codec)) => decrypt_raw(cipher, algorithm, codec, input),
+        None => match Header::parse(input)? {
+            Some((header, header_len)) => match header.version {
+                1 => decrypt_v1(cipher, header, input, header_len),
+                version => Err(AlpacaError::UnsupportedVersion(version)),
+            },
+            None => {
+                let nonce = nonce.ok_or(AlpacaError::MalformedKey(
+                    "Files without a header need the nonce part of the key (key#nonce), \
+                     or --raw if they were written with --no-header",
+                ))?;
+                decrypt_legacy(cipher, &nonce, input)
+            }
+        },
+    }
+}
+
+/// Version 1: AES-GCM over the compressed plaintext, with the header as associated data
+fn decrypt_v1(
+    cipher: &Aes128Gcm,
+    header: Header,
+    input: &[u8],
+    header_len: usize,
+) -> Result<Vec<u8>, AlpacaError> {
+    let (aad, ciphertext) = input.split_at(header_len);
+    if ciphertext.len() < GCM_TAG_LEN {
+        return Err(AlpacaError::Truncated);
+    }
+    let nonce = GcmNonce::from_exact_iter(header.nonce)
+        .ok_or(AlpacaError::InvalidHeader("Nonce must be 12 bytes long"))?;
+
+    let compressed = if header.auth_only {
+        let (plaintext, tag) = ciphertext.split_at(ciphertext.len() - GCM_TAG_LEN);
+        cipher
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: tag,
+                    aad: &[aad, plaintext].concat(),
+                },
+            )
+            .map_err(|_| AlpacaError::AuthenticationFailed)?;
+        plaintext.to_vec()
+    } else {
+        cipher
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| AlpacaError::AuthenticationFailed)?
+    };
+    let plainbytes = decompress(header.codec, compressed)?;
+
+    if let Some(sha256) = header.sha256 {
+        if Sha256::digest(&plainbytes)[..] != sha256 {
+            return Err(AlpacaError::ChecksumMismatch);
+        }
+    }
+    Ok(plainbytes)
+}
+
+/// Files written with `--no-header`: the nonce followed by the ciphertext, without associated
+/// data. Nothing in the file says how it was made, so the algorithm and codec come from the user.
+fn decrypt_raw(
+    cipher: &Aes128Gcm,
+    algorithm: Algorithm,
+    codec: Codec,
+    input: &[u8],
+) -> Result<Vec<u8>, AlpacaError> {
+    let nonce_len = match algorithm {
+        Algorithm::Aes128Gcm => GCM_NONCE_LEN,
+    };
+    if input.len() < nonce_len + GCM_TAG_LEN {
+        return Err(AlpacaError::Truncated);
+    }
+    let (nonce, ciphertext) = input.split_at(nonce_len);
+
+    let compressed = cipher
+        .decrypt(GcmNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| AlpacaError::AuthenticationFailed)?;
+    decompress(codec, compressed)
+}
+
+fn decompress(codec: Codec, input: Vec<u8>) -> Result<Vec<u8>, AlpacaError> {
+    match codec {
+        Codec::None => Ok(input),
+        Codec::Gzip => gzip(&input, GzipMode::Decompress),
+    }
+}
+
+/// Files written before the header existed: AES-GCM without associated data, then gzip over
+/// the ciphertext. The nonce only exists in the `key#nonce` string given to the user.
+fn decrypt_legacy(cipher: &Aes128Gcm, nonce: &[u8], input: &[u8]) -> Result<Vec<u8>, AlpacaError> {
+    let nonce = GcmNonce::from_exact_iter(nonce.iter().copied())
+        .ok_or(AlpacaError::MalformedKey("Nonce must be 12 bytes long"))?;
+
+    if input.len() < GZIP_MIN_LEN {
+        return Err(AlpacaError::Truncated);
+    }
+
+    let input = gzip(input, GzipMode::Decompress)?;
+    if input.len() < GCM_TAG_LEN {
+        return Err(AlpacaError::Truncated);
+    }
+    cipher
+        .decrypt(&nonce, input.as_ref())
+        .map_err(|_| AlpacaError::AuthenticationFailed)
+}
+
+#[derive(Deserialize, Serialize)]
+struct Schematic {
+    action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+
+    filepath: PathBuf,
+    /// Where the result goes instead of next to `filepath`, resolved against the same root
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<PathBuf>,
+    /// Codec for an ENCRYPT entry, `none` or `gzip`. Picked by size and ratio when missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<Codec>,
+}
+
+/// Resolves an entry's `filepath` and `output` against its root
+fn resolve_entry(schematic: &Schematic) -> Result<(PathBuf, Option<PathBuf>), AlpacaError> {
+    let root = schematic.root.as_deref();
+    let filepath = resolve_path(root, &schematic.filepath)?;
+    let output = schematic
+        .output
+        .as_deref()
+        .map(|output| resolve_path(root, output))
+        .transpose()?;
+    Ok((filepath, output))
+}
+
+/// Resolves a schematic entry's `filepath` against its `root`. Under a root, `.` and `..` are
+/// resolved without touching the filesystem and the result has to stay inside the root, so an
+/// entry can't reach files the root wasn't meant to cover. An unknown root is an error.
+///
+/// `${VAR}` in `filepath` is replaced by the environment variable first.
+fn resolve_path(root: Option<&str>, filepath: &Path) -> Result<PathBuf, AlpacaError> {
+    let filepath = expand_env(filepath)?;
+    let base = match root.map(str::to_uppercase).as_deref() {
+        Some("HOME") => dirs::home_dir(),
+        Some("CONFIG" | "ROAMING") => dirs::config_dir(),
+        Some("CACHE" | "LOCAL") => dirs::cache_dir(),
+        Some("TEMP" | "TMP") => Some(std::env::temp_dir()),
+        Some(_) => {
+            return Err(AlpacaError::InvalidPath(
+                "Unknown root, expected HOME, CONFIG, ROAMING, CACHE, LOCAL, TEMP or TMP",
+            ))
+        }
+        None => return Ok(filepath),
+    }
+    .ok_or(AlpacaError::InvalidPath(
+        "The root directory doesn't exist on this system",
+    ))?;
+
+    let mut relative = PathBuf::new();
+    for component in filepath.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !relative.pop() {
+                    return Err(AlpacaError::InvalidPath(
+                        "Path climbs out of its root directory",
+                    ));
+                }
+            }
+            Component::Prefix(_) | Component::RootDir => {
+                return Err(AlpacaError::InvalidPath(
+                    "Path must be relative when a root is given",
+                ))
+            }
+        }
+    }
+    Ok(base.join(relative))
+}
+
+/// Replaces every `${VAR}` in `filepath` with the value of the environment variable `VAR`
+fn expand_env(filepath: &Path) -> Result<PathBuf, AlpacaError> {
+    let Some(mut rest) = filepath.to_str() else {
+        return Ok(filepath.to_path_buf());
+    };
+
+    let mut expanded = String::new();
+    while let Some(start) = rest.find("${") {
+        let len = rest[start..].find('}').ok_or(AlpacaError::InvalidPath(
+            "Path has a '${' without a closing '}'",
+        ))?;
+        let name = &rest[start + 2..start + len];
+        let value = std::env::var(name).map_err(|_| AlpacaError::UnsetVariable(name.to_owned()))?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&value);
+        rest = &rest[start + len + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(PathBuf::from(expanded))
+}
+
+/// Whether an entry's filepath is a glob pattern rather than a single file. A file that exists
+/// under that exact name, like `photo [1].jpg`, is taken literally.
+fn is_glob(schematic: &Schematic) -> bool {
+    if !schematic
+        .filepath
+        .to_string_lossy()
+        .contains(['*', '?', '['])
+    {
+        return false;
+    }
+    match resolve_path(schematic.root.as_deref(), &schematic.filepath) {
+        Ok(filepath) => !filepath.exists() && !with_alp_suffix(filepath).exists(),
+        Err(_) => true,
+    }
+}
+
+/// Turns an entry whose filepath is a glob pattern into one entry per matching file. The
+/// matches are already resolved against the entry's root. A DECRYPT entry's key goes to every
+/// match, for files encrypted with one shared key.
+fn expand_glob(schematic: &Schematic) -> Result<Vec<Schematic>, AlpacaError> {
+    let pattern = resolve_path(schematic.root.as_deref(), &schematic.filepath)?;
+    let pattern = pattern.to_str().ok_or(AlpacaError::InvalidPath(
+        "Glob pattern is not valid Unicode",
+    ))?;
+    let matches: Vec<PathBuf> = glob::glob(pattern)
+        .map_err(|_| AlpacaError::InvalidPath("Path is not a valid glob pattern"))?
+        // Directories that can't be read just don't contribute matches
+        .filter_map(Result::ok)
+        .filter(|filepath| filepath.is_file())
+        .collect();
+    if schematic.output.is_some() && matches.len() > 1 {
+        return Err(AlpacaError::InvalidPath(
+            "Pattern matches more than one file, but the entry only has one output",
+        ));
+    }
+    let output = schematic
+        .output
+        .as_deref()
+        .map(|output| resolve_path(schematic.root.as_deref(), output))
+        .transpose()?;
+
+    Ok(matches
+        .into_iter()
+        .map(|filepath| Schematic {
+            action: schematic.action.clone(),
+            root: None,
+            key: schematic.key.clone(),
+            filepath,
+            output: output.clone(),
+            compression: schematic.compression,
+        })
+        .collect())
+}
+
+/// Loads a schematic and expands its includes. An item of the form `- include: other.yaml` is
+/// replaced by the entries of that schematic, resolved relative to the including file.
+///
+/// Every entry is paired with the schematic file it came from. `including` holds the chain of
+/// files currently being expanded, to catch cycles. `visited` holds every file loaded so far, so
+/// a schematic shared by several others only contributes its entries once.
+///
+/// On failure, the error is returned along with the schematic file that caused it.
+fn load_schematic(
+    filepath: &Path,
+    including: &mut Vec<PathBuf>,
+    visited: &mut HashSet<PathBuf>,
+) -> Result<Vec<(PathBuf, Schematic)>, (PathBuf, AlpacaError)> {
+    let fail = |err| (filepath.to_path_buf(), err);
+
+    // `-` reads the schematic from stdin, its includes are relative to the working directory
+    let from_stdin = filepath == Path::new("-");
+    let entries: Vec<serde_yaml::Value> = if from_stdin {
+        serde_yaml::from_reader(std::io::stdin().lock())
+            .map_err(|err| fail(AlpacaError::InvalidSchematic(err)))?
+    } else {
+        let canonical = filepath
+            .canonicalize()
+            .map_err(|err| fail(AlpacaError::Io("Failed to open file", err)))?;
+        if including.contains(&canonical) {
+            return Err(fail(AlpacaError::IncludeCycle));
+        }
+        // Included twice, e.g. by two schematics that share it, its entries are already in
+        if !visited.insert(canonical.clone()) {
+            return Ok(Vec::new());
+        }
+
+        let file = File::open(filepath)
+            .map_err(|err| fail(AlpacaError::Io("Failed to open file", err)))?;
+        let reader = BufReader::new(file);
+
+        let entries = serde_yaml::from_reader(reader)
+            .map_err(|err| fail(AlpacaError::InvalidSchematic(err)))?;
+        including.push(canonical);
+        entries
+    };
+    let mut schematics = Vec::new();
+    for entry in entries {
+        if let Some(include) = entry.get("include") {
+            let include: PathBuf = serde_yaml::from_value(include.clone())
+                .map_err(|err| fail(AlpacaError::InvalidSchematic(err)))?;
+            let include = match filepath.parent() {
+                Some(parent) => parent.join(include),
+                None => include,
+            };
+            schematics.extend(load_schematic(&include, including, visited)?);
+        } else {
+            // Deserialize YAML data into Schematic
+            let schematic: Schematic = serde_yaml::from_value(entry)
+                .map_err(|err| fail(AlpacaError::InvalidSchematic(err)))?;
+            schematics.push((filepath.to_path_buf(), schematic));
+        }
+    }
+    if !from_stdin {
+        including.pop();
+    }
+
+    Ok(schematics)
+}
+
+/// Drops entries that would run the same action on the same file as an earlier one. They would
+/// otherwise run at the same time, racing each other and printing keys that don't match.
+fn dedup_entries(schematics: Vec<(PathBuf, Schematic)>) -> Vec<(PathBuf, Schematic)> {
+    let mut seen = HashSet::new();
+    schematics
+        .into_iter()
+        .filter(|(source, schematic)| {
+            // Entries that don't resolve are left for the run to report
+            let Ok((filepath, _)) = resolve_entry(schematic) else {
+                return true;
+            };
+            let filepath = filepath.canonicalize().unwrap_or(filepath);
+            let first = seen.insert((schematic.action.to_uppercase(), filepath));
+            if !first {
+                log::warn!(
+                    "Skipped \'{}\' in {}, an earlier entry already does the same",
+                    schematic.filepath.display(),
+                    source.display()
+                );
+            }
+            first
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Outcome {
+    Encrypted,
+    Decrypted,
+    Skipped,
+    Failed,
+}
+
+impl Outcome {
+    /// The outcome as a word for stdout: green when a file was processed, yellow when it was
+    /// skipped and red when it failed. Plain when stdout isn't a terminal or NO_COLOR is set,
+    /// add `.for_stderr()` when printing to stderr. Padded to the longest word, so the paths
+    /// after it line up.
+    fn styled(self) -> console::StyledObject<String> {
+        let (word, color) = match self {
+            Outcome::Encrypted => ("Encrypted", console::Color::Green),
+            Outcome::Decrypted => ("Decrypted", console::Color::Green),
+            Outcome::Skipped => ("Skipped", console::Color::Yellow),
+            Outcome::Failed => ("Failed", console::Color::Red),
+        };
+        console::style(format!("{:<9}", word)).fg(color)
+    }
+}
+
+fn main() {
+    let arguments = Args::parse();
+    let error_format = arguments.error_format;
+    let yes = arguments.yes;
+    let verbose = arguments.verbose;
+    let timings = arguments.timings;
+    let key_encoding = arguments.key_encoding;
+    let temp_dir = arguments.temp_dir;
+    if let Some(temp_dir) = &temp_dir {
+        if !temp_dir.is_dir() {
+            let err = AlpacaError::InvalidPath("--temp-dir must be an existing directory");
+            exit_with_error(error_format, temp_dir, &err);
+        }
+    }
+    let audit_log = arguments.log_file.map(|log_file| {
+        AuditLog::open(&log_file).unwrap_or_else(|err| {
+            let err = AlpacaError::Io("Failed to open log file", err);
+            exit_with_error(error_format, &log_file, &err)
+        })
+    });
+
+    // Diagnostics go through `log` on stderr, keys and results stay on stdout
+    // --timings only turns on its own lines, not everything --verbose shows
+    let default_filter = match (verbose, timings) {
+        (true, _) => "info",
+        (false, true) => "warn,timings=info",
+        (false, false) => "warn",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
+        .format(|buf, record| match record.level() {
+            log::Level::Error => writeln!(buf, "{}: {}", error_label(), record.args()),
+            log::Level::Warn => {
+                let label = console::style("Warning").yellow().for_stderr();
+                writeln!(buf, "{}: {}", label, record.args())
+            }
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
+    interrupt::install();
+
+    let makes_keys = matches!(
+        arguments.command,
+        Command::Encrypt { .. }
+            | Command::LoadSchematic { .. }
+            | Command::EncryptText { .. }
+            | Command::Keygen { .. }
+    );
+    if makes_keys {
+        check_rng().unwrap_or_else(|err| exit_with_general_error(error_format, &err));
+        if matches!(arguments.command, Command::Encrypt { seed: Some(_), .. }) {
+            log::info!("Keys and nonces come from an RNG seeded with --seed, not the OS RNG");
+        } else {
+            log::info!("Keys and nonces come from the operating system RNG (getrandom)");
+        }
+    }
+
+    match arguments.command {
+        Command::Encrypt {
+            key,
+            key_file,
+            secret_file,
+            key_exec,
+            print_key_only,
+            qr,
+            recipients,
+            nonce,
+            seed,
+            i_know_what_im_doing: _,
+            in_place,
+            verify,
+            output_dir,
+            max_file_size,
+            since,
+            hint,
+            checksum,
+            dedup,
+            compress_min_size,
+            min_compression_ratio,
+            name_template,
+            hash_name,
+            no_header,
+            auth_only,
+            continue_on_error,
+            dry_run,
+            manifest,
+            manifest_include_keys,
+            filepaths,
+        } => {
+            if nonce.is_some() && filepaths.len() > 1 {
+                Args::command()
+                    .error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        "--nonce can only be used with a single file",
+                    )
+                    .exit();
+            }
+
+            let key_exec = key_exec.map(|command| match shell_words::split(&command) {
+                Ok(args) if !args.is_empty() => args,
+                _ => Args::command()
+                    .error(
+                        clap::error::ErrorKind::ValueValidation,
+                        "--key-exec must be a command line, e.g. \"pass insert -e backup/{file}\"",
+                    )
+                    .exit(),
+            });
+
+            let secret = secret_file.map(|secret_file| {
+                read_secret(&secret_file)
+                    .unwrap_or_else(|err| exit_with_error(error_format, &secret_file, &err))
+            });
+
+            let shared_key = match (key, key_file) {
+                (Some(key), _) => Some(key),
+                (None, Some(key_file)) => {
+                    let key = std::fs::read_to_string(&key_file)
+                        .map_err(|err| AlpacaError::Io("Failed to read key file", err))
+                        .and_then(|key| parse_gcm_key(&key))
+                        .unwrap_or_else(|err| exit_with_error(error_format, &key_file, &err));
+                    Some(key)
+                }
+                (None, None) => None,
+            };
+            if nonce.is_some() {
+                log::warn!(
+                    "Using a caller-supplied nonce. Encrypting two different files \
+                     with the same key and nonce lets anyone recover both plaintexts and \
+                     forge ciphertexts. Only do this to produce test vectors."
+                );
+            }
+
+            if in_place && !dry_run {
+                let what = match filepaths.as_slice() {
+                    [filepath] => format!("\'{}\'", filepath.display()),
+                    _ => format!("{} files", filepaths.len()),
+                };
+                if !confirm_replace(&what, yes, error_format) {
+                    eprintln!("Aborted.");
+                    std::process::exit(1);
+                }
+            }
+
+            let options = EncryptOptions {
+                hint,
+                checksum,
+                in_place,
+                verify,
+                output_dir,
+                name_template,
+                output: None,
+                hash_name,
+                compress_min_size,
+                min_compression_ratio,
+                raw: no_header,
+                auth_only,
+                recipients,
+                codec: None,
+                temp_dir,
+            };
+            let mut rng: Box<dyn CryptoRngCore> = match seed {
+                Some(seed) => {
+                    log::warn!(
+                        "Using --seed, every key and nonce of this run can be recomputed \
+                         from the seed. Only do this to produce test vectors."
+                    );
+                    Box::new(StdRng::seed_from_u64(seed))
+                }
+                None => Box::new(OsRng),
+            };
+            let manifest = manifest.map(|path| {
+                let manifest = if dedup {
+                    Manifest::replacing(&path, manifest_include_keys).unwrap_or_else(|err| {
+                        let err = AlpacaError::Io("Failed to read the previous manifest", err);
+                        exit_with_error(error_format, &path, &err)
+                    })
+                } else {
+                    Manifest::new(manifest_include_keys)
+                };
+                (path, manifest)
+            });
+            let mut skipped_files = 0;
+            let mut skipped_bytes = 0;
+            let mut unmodified = 0;
+            let mut encrypted = 0;
+            let mut failed = 0;
+            let mut total_timings = Timings::default();
+            for filepath in &filepaths {
+                if interrupt::requested() {
+                    break;
+                }
+                if let Some(size) = oversized(filepath, max_file_size) {
+                    report_oversized(filepath, size);
+                    skipped_files += 1;
+                    skipped_bytes += size;
+                    continue;
+                }
+                if unmodified_since(filepath, since) {
+                    unmodified += 1;
+                    continue;
+                }
+                let previous = manifest.as_ref().filter(|_| dedup);
+                if previous
+                    .is_some_and(|(_, manifest)| unchanged_since_encrypted(filepath, manifest))
+                {
+                    log::warn!(
+                        "Skipped \'{}\', unchanged since it was last encrypted",
+                        filepath.display()
+                    );
+                    continue;
+                }
+                if dry_run {
+                    match plan_encrypt(filepath, &options) {
+                        Ok((newpath, codec)) => println!(
+                            "Would encrypt \'{}\' to \'{}\' ({}, compression: {}){}",
+                            filepath.display(),
+                            newpath.display(),
+                            Algorithm::Aes128Gcm,
+                            codec,
+                            if in_place {
+                                ", removing the original"
+                            } else {
+                                ""
+                            }
+                        ),
+                        Err(err) if continue_on_error => {
+                            report_error(error_format, filepath, &err);
+                            failed += 1;
+                        }
+                        Err(err) => exit_with_error(error_format, filepath, &err),
+                    }
+                    continue;
+                }
 
-    if let Some(ext) = previous_extension {
-        let newpath = filepath.with_extension(format!("{}.alp", ext.to_string_lossy()));
-        std::fs::rename(filepath, &newpath).expect("Failed to rename a file");
-        std::fs::write(newpath, output).expect("Failed to write encrypted data");
-    } else {
-        let newpath = filepath.with_extension("alp");
-        std::fs::rename(filepath, &newpath).expect("Failed to rename a file");
-        std::fs::write(newpath, output).expect("Failed to write encrypted data");
-    };
-}
+                let (key, salt) = match &secret {
+                    Some(secret) => {
+                        let mut salt = vec![0; SALT_LEN];
+                        rng.fill_bytes(&mut salt);
+                        (derive_key(secret, &salt), Some(salt))
+                    }
+                    None => (
+                        shared_key.unwrap_or_else(|| aes_gcm::Aes128Gcm::generate_key(&mut rng)),
+                        None,
+                    ),
+                };
+                let nonce = nonce.unwrap_or_else(|| aes_gcm::Aes128Gcm::generate_nonce(&mut rng));
 
-fn decrypt(mut filepath: PathBuf, key: &str) {
-    if !filepath.exists() {
-        let trypath = PathBuf::from(format!("{}.alp", filepath.display()));
-        filepath = trypath;
-    }
-    let creds: Vec<&str> = key.split('#').collect();
-    let key = hex::decode(creds[0]).expect("Malformed key");
-    let nonce = hex::decode(creds[1]).expect("Malformed key(nonce)");
-    let nonce = aes_gcm::Nonce::from_slice(&nonce);
+                let start = Instant::now();
+                // Hand the key off before encrypting, so a failing command can't leave an
+                // encrypted file behind whose key went nowhere
+                let result = match &key_exec {
+                    Some(command) => run_key_exec(command, filepath, &key_encoding.encode(&key)),
+                    None => Ok(()),
+                }
+                .and_then(|()| encrypt(filepath, &key, &nonce, salt.as_deref(), &options));
+                if let Some(log) = &audit_log {
+                    let result = result.as_ref().map(|_| ());
+                    // A recipient file's own key opens nothing, recipients decrypt with theirs
+                    let key = options.recipients.is_empty().then_some(key.as_slice());
+                    log.record("encrypt", filepath, result, key, start.elapsed());
+                }
+                let (written, stages) = match result {
+                    Ok(done) => done,
+                    Err(err) if continue_on_error => {
+                        report_error(error_format, filepath, &err);
+                        failed += 1;
+                        continue;
+                    }
+                    Err(err) => {
+                        // What was encrypted before the failure still needs to be found
+                        if let Some((path, manifest)) = &manifest {
+                            write_manifest(manifest, path, error_format);
+                        }
+                        exit_with_error(error_format, filepath, &err)
+                    }
+                };
+                encrypted += 1;
+                if let Some((_, manifest)) = &manifest {
+                    let key = options
+                        .recipients
+                        .is_empty()
+                        .then(|| key_encoding.encode(&key));
+                    manifest.record(filepath, &written, key);
+                }
+                if verbose {
+                    let what = format!("Encrypted \'{}\'", filepath.display());
+                    report_timing(&what, written.size, start.elapsed());
+                }
+                if timings {
+                    stages.report(&filepath.display().to_string());
+                    total_timings += stages;
+                }
 
-    let cipher = Aes128Gcm::new_from_slice(&key).expect("Failed to initialize cipher");
+                let print_key = shared_key.is_none()
+                    && key_exec.is_none()
+                    && secret.is_none()
+                    && options.recipients.is_empty();
+                if print_key_only {
+                    let status = Outcome::Encrypted.styled().for_stderr();
+                    eprintln!("{} \'{}\'", status, filepath.display());
+                    println!("{}", key_encoding.encode(&key));
+                } else if filepaths.len() == 1 {
+                    println!("Done.");
+                    if print_key {
+                        println!("Key: {}", key_encoding.encode(&key));
+                    }
+                } else if print_key {
+                    println!(
+                        "{} \'{}\' with key \'{}\'",
+                        Outcome::Encrypted.styled(),
+                        filepath.display(),
+                        key_encoding.encode(&key)
+                    );
+                } else {
+                    println!("{} \'{}\'", Outcome::Encrypted.styled(), filepath.display());
+                }
+                if qr {
+                    print_qr(&key_encoding.encode(&key));
+                }
+            }
+            if skipped_files > 0 {
+                log::warn!(
+                    "Skipped {} files over --max-file-size, {} bytes in total",
+                    skipped_files,
+                    skipped_bytes
+                );
+            }
+            if unmodified > 0 {
+                log::warn!("Skipped {} files not modified since --since", unmodified);
+            }
+            if timings && encrypted > 1 {
+                total_timings.report("Total");
+            }
+            if let Some((path, manifest)) = &manifest {
+                write_manifest(manifest, path, error_format);
+            }
+            if continue_on_error && filepaths.len() > 1 && !dry_run {
+                eprintln!(
+                    "Encrypted {} of {} files, {} failed",
+                    encrypted,
+                    filepaths.len(),
+                    failed
+                );
+            }
+            if interrupt::requested() {
+                std::process::exit(interrupt::EXIT_STATUS);
+            }
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::Decrypt {
+            key,
+            secret_file,
+            ask_each,
+            stdout,
+            in_place,
+            raw,
+            legacy,
+            algorithm,
+            compression,
+            suffix,
+            output,
+            dry_run,
+            filepath,
+        } => {
+            let naming = match (suffix, output) {
+                (Some(suffix), _) => DecryptNaming::AppendSuffix(suffix),
+                (None, Some(output)) => DecryptNaming::Exact(output),
+                (None, None) => DecryptNaming::StripAlp,
+            };
+            let options = DecryptOptions {
+                in_place,
+                stdout,
+                format: FormatFlags {
+                    raw,
+                    legacy,
+                    algorithm,
+                    codec: compression,
+                },
+                temp_dir,
+                naming,
+            };
 
-    let input = std::fs::read(&filepath).expect("Error reading input file");
+            if ask_each {
+                if !std::io::stdin().is_terminal() {
+                    Args::command()
+                        .error(
+                            clap::error::ErrorKind::ArgumentConflict,
+                            "--ask-each needs a terminal to ask for keys",
+                        )
+                        .exit();
+                }
+                let filepaths = encrypted_files_in(&filepath)
+                    .unwrap_or_else(|err| exit_with_error(error_format, &filepath, &err));
+                if in_place
+                    && !confirm_replace(
+                        &format!("the .alp files in \'{}\'", filepath.display()),
+                        yes,
+                        error_format,
+                    )
+                {
+                    eprintln!("Aborted.");
+                    std::process::exit(1);
+                }
 
-    let input = gzip(&input, GzipMode::Decompress);
-    let plainbytes = cipher
-        .decrypt(nonce, input.as_ref())
-        .expect("Failed to decrypt");
+                let mut decrypted = 0;
+                let mut failed = 0;
+                for filepath in &filepaths {
+                    if interrupt::requested() {
+                        break;
+                    }
+                    // The hint is only there to jog the user's memory, a file whose header
+                    // can't be read just gets asked for without one
+                    let hint = std::fs::read(filepath)
+                        .ok()
+                        .and_then(|input| Header::parse(&input).ok().flatten())
+                        .and_then(|(header, _)| header.hint);
+                    let Some(key) = ask_key(filepath, hint.as_deref()) else {
+                        println!("{} \'{}\'", Outcome::Skipped.styled(), filepath.display());
+                        continue;
+                    };
+                    let key = DecryptKey::Text(key);
+                    let start = Instant::now();
+                    let result = decrypt(filepath.clone(), &key, &options);
+                    if let Some(log) = &audit_log {
+                        let result = result.as_ref().map(|_| ());
+                        let given = key.given_key();
+                        log.record(
+                            "decrypt",
+                            filepath,
+                            result,
+                            given.as_deref(),
+                            start.elapsed(),
+                        );
+                    }
+                    match result {
+                        Ok(_) => {
+                            println!("{} \'{}\'", Outcome::Decrypted.styled(), filepath.display());
+                            decrypted += 1;
+                        }
+                        Err(err) => {
+                            report_error(error_format, filepath, &err);
+                            failed += 1;
+                        }
+                    }
+                }
+                println!("Decrypted {} of {} files", decrypted, filepaths.len());
+                if interrupt::requested() {
+                    std::process::exit(interrupt::EXIT_STATUS);
+                }
+                if failed > 0 {
+                    std::process::exit(1);
+                }
+                return;
+            }
 
-    let file_extension = filepath.extension();
-    if let Some(ext) = file_extension {
-        if ext == "alp" {
-            let newpath = filepath.with_extension("");
-            std::fs::rename(filepath, &newpath).expect("Failed to rename a file");
-            std::fs::write(newpath, plainbytes).expect("Failed to write decrypted data");
+            let key = decrypt_key(key, secret_file, error_format);
+            if dry_run {
+                let (filepath, target, description) =
+                    plan_decrypt(filepath.clone(), &key, &options)
+                        .unwrap_or_else(|err| exit_with_error(error_format, &filepath, &err));
+                let target = match target {
+                    Some(target) => format!("to \'{}\'", target.display()),
+                    None => "to stdout".to_owned(),
+                };
+                println!(
+                    "Would decrypt \'{}\' {} ({}){}",
+                    filepath.display(),
+                    target,
+                    description,
+                    if in_place {
+                        ", removing the original"
+                    } else {
+                        ""
+                    }
+                );
+                return;
+            }
+            if in_place
+                && !confirm_replace(&format!("\'{}\'", filepath.display()), yes, error_format)
+            {
+                eprintln!("Aborted.");
+                std::process::exit(1);
+            }
+            let start = Instant::now();
+            let result = decrypt(filepath.clone(), &key, &options);
+            if let Some(log) = &audit_log {
+                let result = result.as_ref().map(|_| ());
+                let given = key.given_key();
+                log.record(
+                    "decrypt",
+                    &filepath,
+                    result,
+                    given.as_deref(),
+                    start.elapsed(),
+                );
+            }
+            let (bytes, stages) =
+                result.unwrap_or_else(|err| exit_with_error(error_format, &filepath, &err));
+            if timings {
+                stages.report(&filepath.display().to_string());
+            }
+            if verbose {
+                let what = format!("Decrypted \'{}\'", filepath.display());
+                report_timing(&what, bytes, start.elapsed());
+            }
+            // With --stdout, stdout carries the plaintext and nothing else
+            if !stdout {
+                println!("Done!");
+            }
         }
-    } else {
-        std::fs::write(filepath, plainbytes).expect("Failed to write decrypted data");
-    }
-}
-
-#[derive(Deserialize, Serialize)]
-struct Schematic {
-    action: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    root: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    key: Option<String>,
+        Command::LoadSchematic {
+            in_place,
+            sequential,
+            parallel_files,
+            max_file_size,
+            since,
+            no_verify,
+            summary_only,
+            keys_out,
+            manifest,
+            manifest_include_keys,
+            filepaths,
+        } => {
+            let mut schematics: Vec<(PathBuf, Schematic)> = Vec::new();
+            let mut visited = HashSet::new();
+            for filepath in &filepaths {
+                let entries = load_schematic(filepath, &mut Vec::new(), &mut visited)
+                    .unwrap_or_else(|(filepath, err)| {
+                        exit_with_error(error_format, &filepath, &err)
+                    });
+                schematics.extend(entries);
+            }
 
-    filepath: PathBuf,
-}
+            // Catch typos before half the batch has run
+            let mut invalid = 0;
+            for (source, schematic) in &schematics {
+                if !matches!(
+                    schematic.action.to_uppercase().as_str(),
+                    "ENCRYPT" | "DECRYPT"
+                ) {
+                    let err = AlpacaError::UnknownAction(schematic.action.clone());
+                    report_error_from(error_format, Some(source), &schematic.filepath, &err);
+                    invalid += 1;
+                }
+            }
+            if invalid > 0 {
+                let err = AlpacaError::InvalidEntries {
+                    invalid,
+                    total: schematics.len(),
+                    reason: "have an unknown action",
+                };
+                exit_with_general_error(error_format, &err);
+            }
 
-fn main() {
-    let arguments = Args::parse();
+            let total = schematics.len();
+            let mut expanded = Vec::with_capacity(total);
+            let mut invalid = 0;
+            // Patterns that match nothing don't stop the run, but count as failed entries
+            let mut unmatched = 0;
+            for (source, schematic) in schematics {
+                if !is_glob(&schematic) {
+                    expanded.push((source, schematic));
+                    continue;
+                }
+                match expand_glob(&schematic) {
+                    Ok(matches) if matches.is_empty() => {
+                        let err = AlpacaError::InvalidPath("Pattern matches no files");
+                        report_error_from(error_format, Some(&source), &schematic.filepath, &err);
+                        unmatched += 1;
+                    }
+                    Ok(matches) => {
+                        expanded.extend(matches.into_iter().map(|entry| (source.clone(), entry)))
+                    }
+                    Err(err) => {
+                        report_error_from(error_format, Some(&source), &schematic.filepath, &err);
+                        invalid += 1;
+                    }
+                }
+            }
+            if invalid > 0 {
+                let err = AlpacaError::InvalidEntries {
+                    invalid,
+                    total,
+                    reason: "have a glob pattern that can't be expanded",
+                };
+                exit_with_general_error(error_format, &err);
+            }
+            let schematics = dedup_entries(expanded);
 
-    match arguments {
-        Args::Encrypt { filepath } => {
-            let key = aes_gcm::Aes128Gcm::generate_key(OsRng);
-            let nonce = aes_gcm::Aes128Gcm::generate_nonce(OsRng);
+            if in_place
+                && !confirm_replace(&format!("{} files", schematics.len()), yes, error_format)
+            {
+                eprintln!("Aborted.");
+                std::process::exit(1);
+            }
 
-            encrypt(&filepath, &key, &nonce);
-            println!("Done.\nKey: {}#{}", hex::encode(key), hex::encode(nonce));
-        }
-        Args::Decrypt { key, filepath } => {
-            decrypt(filepath, &key);
-            println!("Done!");
-        }
-        Args::LoadSchematic { filepath } => {
-            let file = File::open(filepath).expect("Failed to open file");
-            let reader = BufReader::new(file);
+            let encrypt_options = EncryptOptions {
+                in_place,
+                verify: !no_verify,
+                temp_dir: temp_dir.clone(),
+                ..Default::default()
+            };
+            let decrypt_options = DecryptOptions {
+                in_place,
+                temp_dir,
+                ..Default::default()
+            };
+            let run_start = Instant::now();
+            let total_bytes = AtomicUsize::new(0);
+            let total_timings = Mutex::new(Timings::default());
+            let exported_keys = Mutex::new(Vec::new());
+            // Keys only have to be printed when they end up nowhere else
+            let keys_saved = keys_out.is_some() || (manifest.is_some() && manifest_include_keys);
+            let manifest = manifest.map(|path| (path, Manifest::new(manifest_include_keys)));
+            let record_timings = |filepath: &Path, stages: Timings| {
+                if timings {
+                    stages.report(&filepath.display().to_string());
+                    *total_timings.lock().unwrap() += stages;
+                }
+            };
+            let skipped_files = AtomicUsize::new(0);
+            let skipped_bytes = AtomicU64::new(0);
+            let skip_oversized = |filepath: &Path| match oversized(filepath, max_file_size) {
+                Some(size) => {
+                    if !summary_only {
+                        report_oversized(filepath, size);
+                    }
+                    skipped_files.fetch_add(1, Ordering::Relaxed);
+                    skipped_bytes.fetch_add(size, Ordering::Relaxed);
+                    true
+                }
+                None => false,
+            };
 
-            // Deserialize YAML data into Vec<Schematic>
-            let schematics: Vec<Schematic> =
-                serde_yaml::from_reader(reader).expect("Failed to parse YAML");
+            let unmodified = AtomicUsize::new(0);
+            let skip_unmodified = |filepath: &Path| {
+                let skip = unmodified_since(filepath, since);
+                if skip {
+                    unmodified.fetch_add(1, Ordering::Relaxed);
+                }
+                skip
+            };
 
-            // Iterate through the schematics
-            schematics.par_iter().for_each(|schematic| {
+            let run_entry = |(source, schematic): &(PathBuf, Schematic)| {
+                if interrupt::requested() {
+                    return Outcome::Skipped;
+                }
                 match schematic.action.to_uppercase().as_str() {
                     "ENCRYPT" => {
-                        let mut filepath = schematic.filepath.clone();
-                        if let Some(root) = &schematic.root {
-                            match root.to_uppercase().as_str() {
-                                "HOME" => {
-                                    let homedir = match dirs::home_dir() {
-                                        Some(home) => home,
-                                        None => return,
-                                    }
-                                    .join(filepath);
-
-                                    filepath = homedir;
-                                }
-                                "CONFIG" | "ROAMING" => {
-                                    let configdir = match dirs::config_dir() {
-                                        Some(config) => config,
-                                        None => return,
-                                    }
-                                    .join(filepath);
-
-                                    filepath = configdir;
-                                }
-                                "CACHE" | "LOCAL" => {
-                                    let cachedir = match dirs::cache_dir() {
-                                        Some(cache) => cache,
-                                        None => return,
-                                    }
-                                    .join(filepath);
-
-                                    filepath = cachedir;
-                                }
-                                "TEMP" | "TMP" => {
-                                    filepath = std::env::temp_dir().join(filepath);
-                                }
-                                _ => {}
+                        let (filepath, output) = match resolve_entry(schematic) {
+                            Ok(resolved) => resolved,
+                            Err(err) => {
+                                report_error_from(
+                                    error_format,
+                                    Some(source),
+                                    &schematic.filepath,
+                                    &err,
+                                );
+                                return Outcome::Failed;
                             }
+                        };
+                        if skip_oversized(&filepath) || skip_unmodified(&filepath) {
+                            return Outcome::Skipped;
                         }
                         let key = aes_gcm::Aes128Gcm::generate_key(OsRng);
                         let nonce = aes_gcm::Aes128Gcm::generate_nonce(OsRng);
 
-                        encrypt(&filepath, &key, &nonce);
-                        println!(
-                            "Encrypted \'{}\' with key \'{}#{}\'",
-                            filepath.display(),
-                            hex::encode(key),
-                            hex::encode(nonce)
-                        );
+                        let options = EncryptOptions {
+                            output: output.clone(),
+                            codec: schematic.compression,
+                            ..encrypt_options.clone()
+                        };
+                        let start = Instant::now();
+                        let result = encrypt(&filepath, &key, &nonce, None, &options);
+                        if let Some(log) = &audit_log {
+                            let result = result.as_ref().map(|_| ());
+                            log.record("encrypt", &filepath, result, Some(&key), start.elapsed());
+                        }
+                        let (written, stages) = match result {
+                            Ok(done) => done,
+                            Err(err) => {
+                                report_error_from(error_format, Some(source), &filepath, &err);
+                                return Outcome::Failed;
+                            }
+                        };
+                        total_bytes.fetch_add(written.size, Ordering::Relaxed);
+                        record_timings(&filepath, stages);
+                        if verbose {
+                            let what = format!("Encrypted \'{}\'", filepath.display());
+                            report_timing(&what, written.size, start.elapsed());
+                        }
+                        if let Some((_, manifest)) = &manifest {
+                            manifest.record(&filepath, &written, Some(key_encoding.encode(&key)));
+                        }
+                        if !(summary_only && keys_saved) {
+                            println!(
+                                "{} \'{}\' with key \'{}\'",
+                                Outcome::Encrypted.styled(),
+                                filepath.display(),
+                                key_encoding.encode(&key)
+                            );
+                        }
+                        if keys_out.is_some() {
+                            exported_keys.lock().unwrap().push(Schematic {
+                                action: "DECRYPT".to_owned(),
+                                root: None,
+                                key: Some(key_encoding.encode(&key)),
+                                filepath: written.path,
+                                output: None,
+                                compression: None,
+                            });
+                        }
+                        Outcome::Encrypted
                     }
                     "DECRYPT" => {
-                        let mut filepath = schematic.filepath.clone();
-                        if let Some(root) = &schematic.root {
-                            match root.to_uppercase().as_str() {
-                                "HOME" => {
-                                    let homedir = match dirs::home_dir() {
-                                        Some(home) => home,
-                                        None => return,
-                                    }
-                                    .join(filepath);
-
-                                    filepath = homedir;
-                                }
-                                "CONFIG" | "ROAMING" => {
-                                    let configdir = match dirs::config_dir() {
-                                        Some(config) => config,
-                                        None => return,
-                                    }
-                                    .join(filepath);
-
-                                    filepath = configdir;
-                                }
-                                "CACHE" | "LOCAL" => {
-                                    let cachedir = match dirs::cache_dir() {
-                                        Some(cache) => cache,
-                                        None => return,
-                                    }
-                                    .join(filepath);
-
-                                    filepath = cachedir;
-                                }
-                                "TEMP" | "TMP" => {
-                                    filepath = std::env::temp_dir().join(filepath);
-                                }
-                                _ => {}
+                        let (filepath, output) = match resolve_entry(schematic) {
+                            Ok(resolved) => resolved,
+                            Err(err) => {
+                                report_error_from(
+                                    error_format,
+                                    Some(source),
+                                    &schematic.filepath,
+                                    &err,
+                                );
+                                return Outcome::Failed;
                             }
+                        };
+                        if skip_oversized(&filepath) || skip_unmodified(&filepath) {
+                            return Outcome::Skipped;
                         }
 
                         let key = match &schematic.key {
-                            Some(key) => key,
-                            None => return,
+                            Some(key) => DecryptKey::Text(key.clone()),
+                            None => {
+                                let err = AlpacaError::MalformedKey("DECRYPT entries need a key");
+                                report_error_from(error_format, Some(source), &filepath, &err);
+                                return Outcome::Failed;
+                            }
+                        };
+
+                        let options = match output {
+                            Some(output) => DecryptOptions {
+                                naming: DecryptNaming::Exact(output),
+                                ..decrypt_options.clone()
+                            },
+                            None => decrypt_options.clone(),
+                        };
+                        let start = Instant::now();
+                        let result = decrypt(filepath.clone(), &key, &options);
+                        if let Some(log) = &audit_log {
+                            let result = result.as_ref().map(|_| ());
+                            let given = key.given_key();
+                            log.record(
+                                "decrypt",
+                                &filepath,
+                                result,
+                                given.as_deref(),
+                                start.elapsed(),
+                            );
+                        }
+                        let (bytes, stages) = match result {
+                            Ok(done) => done,
+                            Err(err) => {
+                                report_error_from(error_format, Some(source), &filepath, &err);
+                                return Outcome::Failed;
+                            }
                         };
+                        total_bytes.fetch_add(bytes, Ordering::Relaxed);
+                        record_timings(&filepath, stages);
+                        if verbose {
+                            let what = format!("Decrypted \'{}\'", filepath.display());
+                            report_timing(&what, bytes, start.elapsed());
+                        }
+                        if !summary_only {
+                            println!("{} \'{}\'", Outcome::Decrypted.styled(), filepath.display());
+                        }
+                        Outcome::Decrypted
+                    }
+                    _ => unreachable!("actions are validated before any entry runs"),
+                }
+            };
+
+            // Iterate through the schematics
+            let outcomes: Vec<Outcome> = if sequential {
+                schematics.iter().map(run_entry).collect()
+            } else if let Some(threads) = parallel_files {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads.into())
+                    .build()
+                    .expect("Failed to start worker threads")
+                    .install(|| schematics.par_iter().map(run_entry).collect())
+            } else {
+                schematics.par_iter().map(run_entry).collect()
+            };
+
+            if let Some(keys_out) = &keys_out {
+                let mut entries = exported_keys.into_inner().unwrap();
+                entries.sort_by(|a, b| a.filepath.cmp(&b.filepath));
+                let yaml = serde_yaml::to_string(&entries).expect("Failed to serialize yaml");
+                // A new file is created readable by its owner only
+                atomic::write(keys_out, yaml.as_bytes(), keys_out, None).unwrap_or_else(|err| {
+                    let err = AlpacaError::Io("Failed to write the keys file", err);
+                    exit_with_error(error_format, keys_out, &err)
+                });
+            }
+            if let Some((path, manifest)) = &manifest {
+                write_manifest(manifest, path, error_format);
+            }
+
+            if verbose {
+                let what = format!("Processed {} entries", outcomes.len());
+                report_timing(&what, total_bytes.into_inner(), run_start.elapsed());
+            }
+            if timings && outcomes.len() > 1 {
+                total_timings.into_inner().unwrap().report("Total");
+            }
+
+            let skipped_files = skipped_files.into_inner();
+            if skipped_files > 0 && !summary_only {
+                log::warn!(
+                    "Skipped {} files over --max-file-size, {} bytes in total",
+                    skipped_files,
+                    skipped_bytes.into_inner()
+                );
+            }
+            let unmodified = unmodified.into_inner();
+            if unmodified > 0 && !summary_only {
+                log::warn!("Skipped {} files not modified since --since", unmodified);
+            }
+
+            let count = |wanted: Outcome| outcomes.iter().filter(|o| **o == wanted).count();
+            let failed = count(Outcome::Failed) + unmatched;
+            if summary_only {
+                println!(
+                    "Encrypted {}, decrypted {}, skipped {}, failed {}",
+                    count(Outcome::Encrypted),
+                    count(Outcome::Decrypted),
+                    count(Outcome::Skipped),
+                    failed
+                );
+            } else if failed > 0 && matches!(error_format, ErrorFormat::Human) {
+                // In JSON every failure already has its own line
+                log::error!(
+                    "{} of {} entries failed",
+                    failed,
+                    outcomes.len() + unmatched
+                );
+            }
+            if interrupt::requested() {
+                std::process::exit(interrupt::EXIT_STATUS);
+            }
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::Verify {
+            key,
+            secret_file,
+            filepath,
+        } => {
+            let key = decrypt_key(key, secret_file, error_format);
+            verify(&filepath, &key)
+                .unwrap_or_else(|err| exit_with_error(error_format, &filepath, &err));
+            println!("'{}' is intact", filepath.display());
+        }
+        Command::Info { filepath } => {
+            let input = std::fs::read(&filepath).unwrap_or_else(|err| {
+                let err = AlpacaError::Io("Error reading input file", err);
+                exit_with_error(error_format, &filepath, &err)
+            });
 
-                        decrypt(filepath.clone(), key);
-                        println!("Decrypted \'{}\'", filepath.display());
+            match Header::parse(&input) {
+                Ok(Some((header, _))) => {
+                    println!("Format version: {}", header.version);
+                    println!("Algorithm: {}", header.algorithm);
+                    println!("Compression: {}", header.codec);
+                    println!("Nonce: {}", hex::encode(&header.nonce));
+                    if let Some(hint) = header.hint {
+                        println!("Hint: {}", hint);
+                    }
+                    if let Some(sha256) = header.sha256 {
+                        println!("SHA-256: {}", hex::encode(sha256));
+                    }
+                    if header.auth_only {
+                        println!("Auth only: contents are stored unencrypted");
+                    }
+                    if let Some(salt) = header.salt {
+                        println!(
+                            "Key: derived from a secret file, salt {}",
+                            hex::encode(salt)
+                        );
+                    }
+                    if header.name.is_some() {
+                        println!("Original name: stored encrypted");
+                    }
+                    if !header.wrapped_keys.is_empty() {
+                        println!("Recipients: {}", header.wrapped_keys.len());
                     }
-                    _ => println!("Unknown action"),
                 }
+                Ok(None) => println!(
+                    "No header, this file was made by Alpaca 0.1 and needs a key#nonce to decrypt"
+                ),
+                Err(err) => exit_with_error(error_format, &filepath, &err),
+            }
+        }
+        Command::EncryptText { key } => {
+            let text = dialoguer::Password::new()
+                .with_prompt("Text to encrypt")
+                .interact()
+                .map(Zeroizing::new)
+                .unwrap_or_else(|err| {
+                    let dialoguer::Error::IO(err) = err;
+                    let err = AlpacaError::Io("Failed to read the text", err);
+                    exit_with_general_error(error_format, &err)
+                });
+            let print_key = key.is_none();
+            let key = key.unwrap_or_else(|| Aes128Gcm::generate_key(OsRng));
+            let nonce = Aes128Gcm::generate_nonce(OsRng);
+
+            let encrypted = encrypt_text(&text, &key, &nonce)
+                .unwrap_or_else(|err| exit_with_general_error(error_format, &err));
+            println!("{}", encrypted);
+            if print_key {
+                println!("Key: {}", key_encoding.encode(&key));
+            }
+        }
+        Command::DecryptText { key, text } => {
+            let key = key.map(Zeroizing::new).unwrap_or_else(|| {
+                dialoguer::Password::new()
+                    .with_prompt("Key")
+                    .interact()
+                    .map(Zeroizing::new)
+                    .unwrap_or_else(|err| {
+                        let dialoguer::Error::IO(err) = err;
+                        let err = AlpacaError::Io("Failed to read the key", err);
+                        exit_with_general_error(error_format, &err)
+                    })
             });
+            let text = text.unwrap_or_else(|| {
+                dialoguer::Input::new()
+                    .with_prompt("Text to decrypt")
+                    .interact_text()
+                    .unwrap_or_else(|err| {
+                        let dialoguer::Error::IO(err) = err;
+                        let err = AlpacaError::Io("Failed to read the text", err);
+                        exit_with_general_error(error_format, &err)
+                    })
+            });
+
+            let decrypted = decrypt_text(&text, &key)
+                .unwrap_or_else(|err| exit_with_general_error(error_format, &err));
+            println!("{}", *decrypted);
+        }
+        Command::Keygen {
+            algorithm,
+            count,
+            with_nonce,
+        } => {
+            for _ in 0..count {
+                let (key, nonce) = match algorithm {
+                    Algorithm::Aes128Gcm => (
+                        aes_gcm::Aes128Gcm::generate_key(OsRng),
+                        aes_gcm::Aes128Gcm::generate_nonce(OsRng),
+                    ),
+                };
+
+                if with_nonce {
+                    println!(
+                        "{}#{}",
+                        key_encoding.encode(&key),
+                        key_encoding.encode(&nonce)
+                    );
+                } else {
+                    println!("{}", key_encoding.encode(&key));
+                }
+            }
         }
-        Args::MakeSchematic => {
+        Command::MakeSchematic { overwrite } => {
             let filename: String = dialoguer::Input::new()
                 .with_prompt("Enter the name of schematic file")
                 .interact()
                 .unwrap();
 
+            if overwrite && Path::new(&filename).exists() {
+                let confirmed = dialoguer::Confirm::new()
+                    .with_prompt(format!(
+                        "\'{}\' already exists, replace its entries with this one?",
+                        filename
+                    ))
+                    .default(false)
+                    .interact()
+                    .unwrap_or(false);
+                if !confirmed {
+                    eprintln!("Aborted.");
+                    std::process::exit(1);
+                }
+            }
+
             let options = ["Encrypt", "Decrypt"];
             let option_selector = dialoguer::Select::new()
                 .with_prompt("Select action")
@@ -332,6 +3130,18 @@ fn main() {
                     .unwrap(),
             };
 
+            let output: String = dialoguer::Input::new()
+                .with_prompt(match root {
+                    Some(_) => {
+                        "Enter the output path AFTER your root directory (empty: next to the file)"
+                    }
+                    None => "Enter full path to write the result to (empty: next to the file)",
+                })
+                .allow_empty(true)
+                .interact()
+                .unwrap();
+            let output = (!output.is_empty()).then(|| PathBuf::from(output));
+
             match options[option_selector] {
                 "Encrypt" => {
                     let entry = Schematic {
@@ -339,6 +3149,8 @@ fn main() {
                         action: "Encrypt".to_owned(),
                         key: None,
                         filepath: PathBuf::from(dir),
+                        output,
+                        compression: None,
                     };
 
                     let yaml = serde_yaml::to_string(&entry).expect("Failed to serialize yaml");
@@ -353,7 +3165,9 @@ fn main() {
                     }
 
                     let mut file = OpenOptions::new()
-                        .append(true)
+                        .append(!overwrite)
+                        .write(true)
+                        .truncate(overwrite)
                         .create(true)
                         .open(filename)
                         .unwrap();
@@ -368,17 +3182,15 @@ fn main() {
                         .unwrap();
 
                     // A little check
-                    {
-                        let creds: Vec<&str> = key.split('#').collect();
-                        hex::decode(creds[0]).expect("Malformed key");
-                        hex::decode(creds[1]).expect("Malformed key(nonce)");
-                    }
+                    parse_key(&key).expect("Malformed key");
 
                     let entry = Schematic {
                         root,
                         action: "Decrypt".to_owned(),
                         key: Some(key),
                         filepath: PathBuf::from(dir),
+                        output,
+                        compression: None,
                     };
 
                     let yaml = serde_yaml::to_string(&entry).expect("Failed to serialize yaml");
@@ -393,7 +3205,9 @@ fn main() {
                     }
 
                     let mut file = OpenOptions::new()
-                        .append(true)
+                        .append(!overwrite)
+                        .write(true)
+                        .truncate(overwrite)
                         .create(true)
                         .open(filename)
                         .unwrap();
@@ -406,3 +3220,641 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f";
+
+    /// Encrypts `plaintext` from a file in `dir`, then decrypts the result and returns it
+    fn round_trip(dir: &Path, plaintext: &[u8], options: &EncryptOptions) -> Vec<u8> {
+        let filepath = dir.join("plain.txt");
+        std::fs::write(&filepath, plaintext).unwrap();
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        let (encrypted, _) = encrypt(&filepath, &key, &nonce, None, options).unwrap();
+        std::fs::remove_file(&filepath).unwrap();
+
+        let decrypt_options = DecryptOptions {
+            format: FormatFlags {
+                raw: options.raw,
+                algorithm: options.raw.then_some(Algorithm::Aes128Gcm),
+                codec: options.raw.then_some(encrypted.codec),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let key = DecryptKey::Text(KEY.to_owned());
+        decrypt(dir.join("plain.txt.alp"), &key, &decrypt_options).unwrap();
+        std::fs::read(&filepath).unwrap()
+    }
+
+    #[test]
+    fn round_trips_every_codec() {
+        let dir = tempfile::tempdir().unwrap();
+        let plaintext = b"round trip me ".repeat(100);
+        for raw in [false, true] {
+            for compress_min_size in [0, usize::MAX] {
+                let options = EncryptOptions {
+                    raw,
+                    compress_min_size,
+                    ..Default::default()
+                };
+                assert_eq!(round_trip(dir.path(), &plaintext, &options), plaintext);
+            }
+        }
+
+        // 0.1 files: gzip over the ciphertext, with the nonce only in the key string
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        let cipher = Aes128Gcm::new(&key);
+        let ciphertext = cipher.encrypt(&nonce, plaintext.as_slice()).unwrap();
+        let legacy = gzip(&ciphertext, GzipMode::Compress).unwrap();
+        assert_eq!(
+            decrypt_data(&cipher, &legacy, Some(nonce.to_vec()), None).unwrap(),
+            plaintext
+        );
+    }
+
+    #[test]
+    fn round_trips_empty_input_in_every_format() {
+        let dir = tempfile::tempdir().unwrap();
+        for (raw, auth_only) in [(false, false), (true, false), (false, true)] {
+            for compress_min_size in [0, usize::MAX] {
+                let options = EncryptOptions {
+                    raw,
+                    auth_only,
+                    compress_min_size,
+                    ..Default::default()
+                };
+                assert!(round_trip(dir.path(), b"", &options).is_empty());
+            }
+        }
+
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        let cipher = Aes128Gcm::new(&key);
+        let ciphertext = cipher.encrypt(&nonce, b"".as_slice()).unwrap();
+        let legacy = gzip(&ciphertext, GzipMode::Compress).unwrap();
+        assert!(decrypt_data(&cipher, &legacy, Some(nonce.to_vec()), None)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn round_trips_without_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = EncryptOptions {
+            raw: true,
+            ..Default::default()
+        };
+        assert_eq!(round_trip(dir.path(), b"raw", &options), b"raw");
+    }
+
+    #[test]
+    fn round_trips_empty_and_large_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = EncryptOptions {
+            checksum: true,
+            ..Default::default()
+        };
+        assert!(round_trip(dir.path(), b"", &options).is_empty());
+
+        let large: Vec<u8> = (0..4_000_000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(round_trip(dir.path(), &large, &options), large);
+    }
+
+    #[test]
+    fn encrypts_empty_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("empty");
+        std::fs::write(&filepath, b"").unwrap();
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        encrypt(&filepath, &key, &nonce, None, &EncryptOptions::default()).unwrap();
+
+        let input = std::fs::read(dir.path().join("empty.alp")).unwrap();
+        let (_, header_len) = Header::parse(&input).unwrap().unwrap();
+        assert_eq!(input.len(), header_len + GCM_TAG_LEN);
+        let cipher = Aes128Gcm::new(&key);
+        assert!(decrypt_data(&cipher, &input, None, None)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn truncated_files_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("cut");
+        std::fs::write(&filepath, b"cut short").unwrap();
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        encrypt(&filepath, &key, &nonce, None, &EncryptOptions::default()).unwrap();
+
+        let input = std::fs::read(dir.path().join("cut.alp")).unwrap();
+        let (_, header_len) = Header::parse(&input).unwrap().unwrap();
+        let cipher = Aes128Gcm::new(&key);
+        assert!(matches!(
+            decrypt_data(&cipher, &input[..header_len + GCM_TAG_LEN - 1], None, None),
+            Err(AlpacaError::Truncated)
+        ));
+
+        let legacy = [0x1f, 0x8b, 8, 0, 0, 0];
+        assert!(legacy.len() < GZIP_MIN_LEN);
+        assert!(matches!(
+            decrypt_data(&cipher, &legacy, Some(nonce.to_vec()), None),
+            Err(AlpacaError::Truncated)
+        ));
+    }
+
+    /// Files written by the build that introduced format version 1, with `--nonce
+    /// 101112131415161718191a1b`. Later builds must keep decrypting them byte for byte.
+    #[test]
+    fn decrypts_v1_fixtures() {
+        const RECIPIENT: &str = "0f0e0d0c0b0a09080706050403020100";
+        let plaintext = include_bytes!("../tests/fixtures/v1/fixture.txt");
+        let fixtures: [(&[u8], &str); 5] = [
+            (include_bytes!("../tests/fixtures/v1/gzip.alp"), KEY),
+            (include_bytes!("../tests/fixtures/v1/uncompressed.alp"), KEY),
+            (
+                include_bytes!("../tests/fixtures/v1/checksum-hint.alp"),
+                KEY,
+            ),
+            (include_bytes!("../tests/fixtures/v1/auth-only.alp"), KEY),
+            (
+                include_bytes!("../tests/fixtures/v1/recipient.alp"),
+                RECIPIENT,
+            ),
+        ];
+        for (input, key) in fixtures {
+            let (cipher, nonce) = open_cipher(&DecryptKey::Text(key.to_owned()), input).unwrap();
+            assert_eq!(
+                decrypt_data(&cipher, input, nonce, None).unwrap(),
+                plaintext
+            );
+        }
+
+        let mut newer = include_bytes!("../tests/fixtures/v1/gzip.alp").to_vec();
+        newer[header::MAGIC.len()] = 2;
+        let cipher = Aes128Gcm::new(&parse_gcm_key(KEY).unwrap());
+        assert!(matches!(
+            decrypt_data(&cipher, &newer, None, None),
+            Err(AlpacaError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn gzip_is_symmetric() {
+        let input = b"compress me, compress me, compress me";
+        let compressed = gzip(input, GzipMode::Compress).unwrap();
+        assert_eq!(gzip(&compressed, GzipMode::Decompress).unwrap(), input);
+        assert!(matches!(
+            gzip(b"not gzip at all", GzipMode::Decompress),
+            Err(AlpacaError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        assert!(parse_gcm_key("not a key").is_err());
+        assert!(parse_gcm_key("0001").is_err());
+        assert!(parse_gcm_nonce(KEY).is_err());
+        assert!(parse_key("zz#00").is_err());
+        assert!(parse_key(&format!("{}#zz", KEY)).is_err());
+    }
+
+    #[test]
+    fn accepts_base64_keys() {
+        let key = parse_gcm_key(KEY).unwrap();
+        let encoded = KeyEncoding::Base64.encode(&key);
+        assert_eq!(parse_gcm_key(&encoded).unwrap(), key);
+    }
+
+    #[test]
+    fn wrong_key_fails_authentication() {
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("secret");
+        std::fs::write(&filepath, b"secret").unwrap();
+        encrypt(&filepath, &key, &nonce, None, &EncryptOptions::default()).unwrap();
+
+        let input = std::fs::read(dir.path().join("secret.alp")).unwrap();
+        let wrong = Aes128Gcm::new(&parse_gcm_key("ffffffffffffffffffffffffffffffff").unwrap());
+        assert!(matches!(
+            decrypt_data(&wrong, &input, None, None),
+            Err(AlpacaError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn tampered_header_fails_authentication() {
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("secret");
+        std::fs::write(&filepath, b"secret").unwrap();
+        let options = EncryptOptions {
+            hint: Some("work".to_owned()),
+            ..Default::default()
+        };
+        encrypt(&filepath, &key, &nonce, None, &options).unwrap();
+
+        let mut input = std::fs::read(dir.path().join("secret.alp")).unwrap();
+        let hint_at = input.windows(4).position(|w| w == b"work").unwrap();
+        input[hint_at] = b'W';
+        assert!(matches!(
+            decrypt_data(&Aes128Gcm::new(&key), &input, None, None),
+            Err(AlpacaError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn round_trips_with_secret_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("secret");
+        std::fs::write(&filepath, b"secret").unwrap();
+        let secret = Zeroizing::new(b"contents of a key file".to_vec());
+        let salt = [5; SALT_LEN];
+        let key = derive_key(&secret, &salt);
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        encrypt(
+            &filepath,
+            &key,
+            &nonce,
+            Some(&salt),
+            &EncryptOptions::default(),
+        )
+        .unwrap();
+        std::fs::remove_file(&filepath).unwrap();
+
+        let wrong = DecryptKey::Secret(Zeroizing::new(b"another file".to_vec()));
+        let options = DecryptOptions::default();
+        assert!(matches!(
+            decrypt(dir.path().join("secret.alp"), &wrong, &options),
+            Err(AlpacaError::AuthenticationFailed)
+        ));
+        decrypt(
+            dir.path().join("secret.alp"),
+            &DecryptKey::Secret(secret),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(&filepath).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn auth_only_keeps_plaintext_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = EncryptOptions {
+            auth_only: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            round_trip(dir.path(), b"public log", &options),
+            b"public log"
+        );
+
+        let key = parse_gcm_key(KEY).unwrap();
+        let mut input = std::fs::read(dir.path().join("plain.txt.alp")).unwrap();
+        let at = input.windows(10).position(|w| w == b"public log").unwrap();
+        input[at] = b'P';
+        assert!(matches!(
+            decrypt_data(&Aes128Gcm::new(&key), &input, None, None),
+            Err(AlpacaError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn recipients_decrypt_with_their_own_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("shared.txt");
+        std::fs::write(&filepath, b"for both").unwrap();
+        let recipients = ["11".repeat(16), "22".repeat(16)];
+        let options = EncryptOptions {
+            recipients: recipients
+                .iter()
+                .map(|key| parse_gcm_key(key).unwrap())
+                .collect(),
+            ..Default::default()
+        };
+        let file_key = Aes128Gcm::generate_key(OsRng);
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        encrypt(&filepath, &file_key, &nonce, None, &options).unwrap();
+
+        let input = std::fs::read(dir.path().join("shared.txt.alp")).unwrap();
+        for recipient in recipients {
+            let (cipher, _) = open_cipher(&DecryptKey::Text(recipient), &input).unwrap();
+            assert_eq!(
+                decrypt_data(&cipher, &input, None, None).unwrap(),
+                b"for both"
+            );
+        }
+        assert!(matches!(
+            open_cipher(&DecryptKey::Text(KEY.to_owned()), &input),
+            Err(AlpacaError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        let encrypted = encrypt_text("hunter2", &key, &nonce).unwrap();
+
+        assert_eq!(*decrypt_text(&encrypted, KEY).unwrap(), "hunter2");
+        assert!(matches!(
+            decrypt_text(&encrypted, &"11".repeat(16)),
+            Err(AlpacaError::AuthenticationFailed)
+        ));
+        assert!(matches!(
+            decrypt_text("not base64!", KEY),
+            Err(AlpacaError::MalformedText(_))
+        ));
+    }
+
+    #[test]
+    fn hash_name_restores_original_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("tax_return.pdf");
+        std::fs::write(&filepath, b"numbers").unwrap();
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        let options = EncryptOptions {
+            hash_name: true,
+            in_place: true,
+            ..Default::default()
+        };
+        encrypt(&filepath, &key, &nonce, None, &options).unwrap();
+
+        let encrypted = encrypted_files_in(dir.path()).unwrap();
+        assert_eq!(encrypted.len(), 1);
+        assert!(!encrypted[0].to_string_lossy().contains("tax_return"));
+        let key = DecryptKey::Text(KEY.to_owned());
+        decrypt(encrypted[0].clone(), &key, &DecryptOptions::default()).unwrap();
+        assert_eq!(std::fs::read(&filepath).unwrap(), b"numbers");
+
+        let cipher = Aes128Gcm::new(&parse_gcm_key(KEY).unwrap());
+        let escape = cipher
+            .encrypt(&name_nonce(&nonce), b"../escaped".as_ref())
+            .unwrap();
+        assert!(matches!(
+            decrypt_name(&cipher, &nonce, &escape),
+            Err(AlpacaError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn expands_glob_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.txt", "b.txt", "c.log"] {
+            std::fs::write(dir.path().join(name), name).unwrap();
+        }
+        let entry = |action: &str| Schematic {
+            action: action.to_owned(),
+            root: None,
+            key: None,
+            filepath: dir.path().join("*.txt"),
+            output: None,
+            compression: None,
+        };
+
+        let mut matches: Vec<PathBuf> = expand_glob(&entry("ENCRYPT"))
+            .unwrap()
+            .into_iter()
+            .map(|schematic| schematic.filepath)
+            .collect();
+        matches.sort();
+        assert_eq!(
+            matches,
+            [dir.path().join("a.txt"), dir.path().join("b.txt")]
+        );
+        assert!(is_glob(&entry("ENCRYPT")));
+        std::fs::write(dir.path().join("*.txt"), "literal").unwrap();
+        assert!(!is_glob(&entry("ENCRYPT")));
+        std::fs::remove_file(dir.path().join("*.txt")).unwrap();
+        assert_eq!(expand_glob(&entry("decrypt")).unwrap().len(), 2);
+        let with_output = Schematic {
+            output: Some(dir.path().join("out.alp")),
+            ..entry("ENCRYPT")
+        };
+        assert!(matches!(
+            expand_glob(&with_output),
+            Err(AlpacaError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn names_decrypted_files() {
+        let alp = Path::new("dir/report.pdf.alp");
+        assert_eq!(
+            decrypted_path(alp, &DecryptNaming::StripAlp),
+            Some(PathBuf::from("dir/report.pdf"))
+        );
+        assert_eq!(
+            decrypted_path(alp, &DecryptNaming::AppendSuffix(".dec".to_owned())),
+            Some(PathBuf::from("dir/report.pdf.dec"))
+        );
+        assert_eq!(
+            decrypted_path(Path::new("report.bin"), &DecryptNaming::StripAlp),
+            None
+        );
+        assert_eq!(
+            decrypted_path(Path::new("notes"), &DecryptNaming::StripAlp),
+            None
+        );
+        let exact = DecryptNaming::Exact(PathBuf::from("out.pdf"));
+        assert_eq!(decrypted_path(alp, &exact), Some(PathBuf::from("out.pdf")));
+    }
+
+    #[test]
+    fn renders_name_templates() {
+        let template = parse_name_template("{stem}-{hash}.{ext}.alp").unwrap();
+        let name = render_name(&template, Path::new("dir/notes.txt"), b"");
+        assert_eq!(name, "notes-e3b0c44298fc1c14.txt.alp");
+
+        assert!(parse_name_template("{size}.alp").is_err());
+        assert!(parse_name_template("{stem.alp").is_err());
+        assert!(parse_name_template("a}b{stem}.alp").is_err());
+        assert!(parse_name_template("{stem}}.alp").is_err());
+        assert!(parse_name_template("out/{stem}.alp").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("notes.txt");
+        std::fs::write(&filepath, b"dated").unwrap();
+        let options = EncryptOptions {
+            name_template: Some("{stem}_{hash}.{ext}.enc".to_owned()),
+            ..Default::default()
+        };
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        let (encrypted, _) = encrypt(&filepath, &key, &nonce, None, &options).unwrap();
+        std::fs::remove_file(&filepath).unwrap();
+        let key = DecryptKey::Text(KEY.to_owned());
+        decrypt(encrypted.path, &key, &DecryptOptions::default()).unwrap();
+        assert_eq!(std::fs::read(&filepath).unwrap(), b"dated");
+
+        let at = |secs| utc_date(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01");
+        assert_eq!(at(951_868_800), "2000-03-01");
+        assert_eq!(at(1_700_000_000), "2023-11-14");
+    }
+
+    #[test]
+    fn parses_since_dates() {
+        assert_eq!(
+            parse_since("2000-03-01").map(utc_date),
+            Ok("2000-03-01".to_owned())
+        );
+        assert_eq!(
+            parse_since("2023-11-14T22:13:20"),
+            Ok(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        assert!(parse_since("2023-02-30").is_err());
+        assert!(parse_since("1969-12-31").is_err());
+        assert!(parse_since("yesterday").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_non_utf8_file_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+        let filepath = dir.path().join(name);
+        std::fs::write(&filepath, b"bytes").unwrap();
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        encrypt(&filepath, &key, &nonce, None, &EncryptOptions::default()).unwrap();
+        std::fs::remove_file(&filepath).unwrap();
+
+        let encrypted = dir
+            .path()
+            .join(std::ffi::OsStr::from_bytes(b"caf\xe9.txt.alp"));
+        let key = DecryptKey::Text(KEY.to_owned());
+        decrypt(encrypted.clone(), &key, &DecryptOptions::default()).unwrap();
+        assert_eq!(std::fs::read(&filepath).unwrap(), b"bytes");
+        std::fs::remove_file(encrypted).unwrap();
+
+        let options = EncryptOptions {
+            hash_name: true,
+            in_place: true,
+            ..Default::default()
+        };
+        let key = parse_gcm_key(KEY).unwrap();
+        encrypt(&filepath, &key, &nonce, None, &options).unwrap();
+        let encrypted = encrypted_files_in(dir.path()).unwrap();
+        assert_eq!(encrypted.len(), 1);
+        let key = DecryptKey::Text(KEY.to_owned());
+        decrypt(encrypted[0].clone(), &key, &DecryptOptions::default()).unwrap();
+        assert_eq!(std::fs::read(&filepath).unwrap(), b"bytes");
+    }
+
+    #[test]
+    fn skips_compression_that_does_not_pay_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let codec_of = |plaintext: &[u8]| {
+            round_trip(dir.path(), plaintext, &EncryptOptions::default());
+            let input = std::fs::read(dir.path().join("plain.txt.alp")).unwrap();
+            Header::parse(&input).unwrap().unwrap().0.codec
+        };
+        let mut noise = vec![0; 4096];
+        OsRng.fill_bytes(&mut noise);
+        assert_eq!(codec_of(&noise), Codec::None);
+        assert_eq!(codec_of(&b"squeeze me ".repeat(400)), Codec::Gzip);
+    }
+
+    #[test]
+    fn decrypts_legacy_files() {
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        let cipher = Aes128Gcm::new(&key);
+        let ciphertext = cipher.encrypt(&nonce, b"from 0.1".as_ref()).unwrap();
+        let input = gzip(&ciphertext, GzipMode::Compress).unwrap();
+
+        let plaintext = decrypt_data(&cipher, &input, Some(nonce.to_vec()), None).unwrap();
+        assert_eq!(plaintext, b"from 0.1");
+        assert!(matches!(
+            decrypt_data(&cipher, &input, None, None),
+            Err(AlpacaError::MalformedKey(_))
+        ));
+    }
+
+    #[test]
+    fn dedup_skips_files_unchanged_since_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("photo.jpg");
+        std::fs::write(&filepath, b"pixels").unwrap();
+        let key = parse_gcm_key(KEY).unwrap();
+        let nonce = Aes128Gcm::generate_nonce(OsRng);
+        let (encrypted, _) =
+            encrypt(&filepath, &key, &nonce, None, &EncryptOptions::default()).unwrap();
+        let path = dir.path().join("manifest.json");
+        let manifest = Manifest::new(false);
+        manifest.record(&filepath, &encrypted, None);
+        manifest.write(&path).unwrap();
+
+        let manifest = Manifest::replacing(&path, false).unwrap();
+        assert!(unchanged_since_encrypted(&filepath, &manifest));
+        std::fs::write(&filepath, b"edited pixels").unwrap();
+        assert!(!unchanged_since_encrypted(&filepath, &manifest));
+    }
+
+    #[test]
+    fn shared_includes_load_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, yaml: &str| std::fs::write(dir.path().join(name), yaml).unwrap();
+        write("common.yaml", "- action: ENCRYPT\n  filepath: d.txt\n");
+        write("b.yaml", "- include: common.yaml\n");
+        write("c.yaml", "- include: common.yaml\n");
+        write("a.yaml", "- include: b.yaml\n- include: c.yaml\n");
+
+        let mut visited = HashSet::new();
+        let a = load_schematic(&dir.path().join("a.yaml"), &mut Vec::new(), &mut visited).unwrap();
+        assert_eq!(a.len(), 1);
+        let common = load_schematic(
+            &dir.path().join("common.yaml"),
+            &mut Vec::new(),
+            &mut visited,
+        )
+        .unwrap();
+        assert!(common.is_empty());
+
+        let mut twice = a;
+        twice.extend(
+            load_schematic(
+                &dir.path().join("b.yaml"),
+                &mut Vec::new(),
+                &mut HashSet::new(),
+            )
+            .unwrap(),
+        );
+        assert_eq!(twice.len(), 2);
+        assert_eq!(dedup_entries(twice).len(), 1);
+    }
+
+    #[test]
+    fn resolve_path_stays_inside_root() {
+        let temp = std::env::temp_dir();
+        assert_eq!(
+            resolve_path(Some("temp"), Path::new("a/./b/../c")).unwrap(),
+            temp.join("a/c")
+        );
+        assert!(resolve_path(Some("TEMP"), Path::new("a/../../etc")).is_err());
+        assert!(resolve_path(Some("TEMP"), Path::new("/etc/passwd")).is_err());
+        assert!(resolve_path(Some("HOMEE"), Path::new("../../../etc/hostname")).is_err());
+        assert_eq!(
+            resolve_path(None, Path::new("../x")).unwrap(),
+            PathBuf::from("../x")
+        );
+    }
+
+    #[test]
+    fn mirrored_path_drops_root_and_rejects_parents() {
+        let mirrored = mirrored_path(Path::new("out"), Path::new("/data/./a.txt")).unwrap();
+        assert_eq!(mirrored, Path::new("out/data/a.txt"));
+        assert!(mirrored_path(Path::new("out"), Path::new("../a.txt")).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use crate::header::Algorithm;
+use crate::Encrypted;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Inventory of the files a run encrypted for `--manifest`, written as one JSON array at the
+/// end. Shared between the threads of a schematic run.
+pub struct Manifest {
+    include_keys: bool,
+    entries: Mutex<Vec<Entry>>,
+    /// Entries of the manifest this run replaces, for `--dedup`
+    previous: Vec<Entry>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Entry {
+    original: PathBuf,
+    output: PathBuf,
+    algorithm: String,
+    codec: String,
+    sha256: String,
+    size: usize,
+    timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+}
+
+impl Manifest {
+    pub fn new(include_keys: bool) -> Manifest {
+        Manifest {
+            include_keys,
+            entries: Mutex::new(Vec::new()),
+            previous: Vec::new(),
+        }
+    }
+
+    /// Like `new`, remembering the entries of the manifest already at `path` so unchanged
+    /// files can be skipped. A missing manifest is an empty one.
+    pub fn replacing(path: &Path, include_keys: bool) -> io::Result<Manifest> {
+        let previous = match std::fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Manifest {
+            previous,
+            ..Manifest::new(include_keys)
+        })
+    }
+
+    /// Whether the previous manifest has `original` with this plaintext hash and its encrypted
+    /// copy is still there. If so its entry is carried over, since this run skips the file.
+    pub fn carry_over(&self, original: &Path, sha256: &[u8; 32]) -> bool {
+        let sha256 = hex::encode(sha256);
+        let Some(entry) = self.previous.iter().find(|entry| {
+            entry.original == original && entry.sha256 == sha256 && entry.output.is_file()
+        }) else {
+            return false;
+        };
+        self.entries
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(entry.clone());
+        true
+    }
+
+    /// Adds `original`, encrypted with `key`. The key is dropped unless keys were asked for.
+    /// Files encrypted for recipients have no key of their own to give out.
+    pub fn record(&self, original: &Path, encrypted: &Encrypted, key: Option<String>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let entry = Entry {
+            original: original.to_path_buf(),
+            output: encrypted.path.clone(),
+            algorithm: Algorithm::Aes128Gcm.to_string(),
+            codec: encrypted.codec.to_string(),
+            sha256: hex::encode(encrypted.sha256),
+            size: encrypted.size,
+            timestamp,
+            key: key.filter(|_| self.include_keys),
+        };
+        self.entries
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(entry);
+    }
+
+    /// Writes the entries sorted by original path, so runs can be diffed
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+        entries.sort_by(|a, b| a.original.cmp(&b.original));
+        let mut json = serde_json::to_string_pretty(&*entries).expect("Failed to serialize json");
+        json.push('\n');
+        // A new file is created readable by its owner only, it may hold keys
+        crate::atomic::write(path, json.as_bytes(), path, None)
+    }
+}
@@ -0,0 +1,65 @@
+use std::io;
+use std::path::Path;
+
+/// Bytes an unprivileged user can still write to the filesystem `dir` is on
+#[cfg(unix)]
+pub fn available(dir: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL terminated and `stats` is only read after statvfs filled it in
+    if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stats = unsafe { stats.assume_init() };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+#[cfg(windows)]
+pub fn available(dir: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let wide: Vec<u16> = dir.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0;
+    // SAFETY: `wide` is a NUL terminated UTF-16 path that outlives the call, the other
+    // outputs are optional
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            wide.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn available(_dir: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Fails with `StorageFull` when `dir` can't take `needed` more bytes, so a write that would
+/// run out of space halfway isn't started. A filesystem that can't be asked lets it through.
+pub fn ensure(dir: &Path, needed: u64) -> io::Result<()> {
+    match available(dir) {
+        Ok(available) if available < needed => Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "'{}' has {} bytes free, {} are needed",
+                dir.display(),
+                available,
+                needed
+            ),
+        )),
+        _ => Ok(()),
+    }
+}